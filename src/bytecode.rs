@@ -0,0 +1,313 @@
+use alloy::{
+    primitives::{keccak256, Address, FixedBytes},
+    providers::Provider,
+};
+use revm::interpreter::opcode::{DUP2, EQ, PUSH1, PUSH32, PUSH4};
+use serde::Serialize;
+use std::fmt::Display;
+
+use crate::{
+    error::{HPError, Result},
+    AlloyProvider,
+};
+
+/// What a privileged function lets the owner do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Capability {
+    Blacklist,
+    Whitelist,
+    TransactionLimits,
+    Fees,
+    Pause,
+    Mint,
+    TradingSwitch,
+    /// Toggles the contract selling its collected fees, not trading
+    FeeSwapSwitch,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::Blacklist => "blacklist",
+            Capability::Whitelist => "whitelist",
+            Capability::TransactionLimits => "transaction limits",
+            Capability::Fees => "fees",
+            Capability::Pause => "pause",
+            Capability::Mint => "mint",
+            Capability::TradingSwitch => "trading switch",
+            Capability::FeeSwapSwitch => "fee swap switch",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Values the owner actions call a privileged function with: every `address` is the holder,
+/// every `bool` is `flag` and every other parameter is `uint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OwnerCall {
+    pub uint: u64,
+    pub flag: bool,
+}
+
+/// Blacklists the holder, sets a zero limit, pauses or turns cooldowns on
+const TRAP: &[OwnerCall] = &[OwnerCall {
+    uint: 0,
+    flag: true,
+}];
+/// Turns trading off
+const DISABLE: &[OwnerCall] = &[OwnerCall {
+    uint: 0,
+    flag: false,
+}];
+/// Fees of 99%, in percent and in basis points as contracts use either
+const EXTREME_FEES: &[OwnerCall] = &[
+    OwnerCall {
+        uint: 99,
+        flag: true,
+    },
+    OwnerCall {
+        uint: 9_900,
+        flag: true,
+    },
+];
+/// The function cannot stop holders from selling
+const NOT_CALLED: &[OwnerCall] = &[];
+
+/// Functions letting the owner change who can trade the token and on which terms,
+/// with the capability they give and the calls the owner actions make
+const RISKY_FUNCTIONS: [(&str, Capability, &[OwnerCall]); 30] = [
+    ("blacklist(address)", Capability::Blacklist, TRAP),
+    (
+        "blacklistAddress(address,bool)",
+        Capability::Blacklist,
+        TRAP,
+    ),
+    ("addToBlacklist(address)", Capability::Blacklist, TRAP),
+    ("setBlacklist(address,bool)", Capability::Blacklist, TRAP),
+    ("setBots(address[])", Capability::Blacklist, TRAP),
+    ("setBots(address[],bool)", Capability::Blacklist, TRAP),
+    ("addBots(address[])", Capability::Blacklist, TRAP),
+    ("addToWhitelist(address)", Capability::Whitelist, NOT_CALLED),
+    (
+        "setWhitelist(address,bool)",
+        Capability::Whitelist,
+        NOT_CALLED,
+    ),
+    ("excludeFromFee(address)", Capability::Whitelist, NOT_CALLED),
+    (
+        "excludeFromFees(address,bool)",
+        Capability::Whitelist,
+        NOT_CALLED,
+    ),
+    (
+        "setMaxTxAmount(uint256)",
+        Capability::TransactionLimits,
+        TRAP,
+    ),
+    (
+        "setMaxTxPercent(uint256)",
+        Capability::TransactionLimits,
+        TRAP,
+    ),
+    (
+        "setMaxWalletSize(uint256)",
+        Capability::TransactionLimits,
+        TRAP,
+    ),
+    (
+        "setMaxWalletAmount(uint256)",
+        Capability::TransactionLimits,
+        TRAP,
+    ),
+    (
+        "updateMaxTxnAmount(uint256)",
+        Capability::TransactionLimits,
+        TRAP,
+    ),
+    ("setFee(uint256,uint256)", Capability::Fees, EXTREME_FEES),
+    ("setFees(uint256,uint256)", Capability::Fees, EXTREME_FEES),
+    ("setTaxFeePercent(uint256)", Capability::Fees, EXTREME_FEES),
+    (
+        "updateBuyFees(uint256,uint256,uint256)",
+        Capability::Fees,
+        EXTREME_FEES,
+    ),
+    (
+        "updateSellFees(uint256,uint256,uint256)",
+        Capability::Fees,
+        EXTREME_FEES,
+    ),
+    ("pause()", Capability::Pause, TRAP),
+    ("unpause()", Capability::Pause, NOT_CALLED),
+    ("mint(address,uint256)", Capability::Mint, NOT_CALLED),
+    ("mint(uint256)", Capability::Mint, NOT_CALLED),
+    (
+        "setTradingEnabled(bool)",
+        Capability::TradingSwitch,
+        DISABLE,
+    ),
+    ("enableTrading()", Capability::TradingSwitch, NOT_CALLED),
+    ("openTrading()", Capability::TradingSwitch, NOT_CALLED),
+    (
+        "setSwapEnabled(bool)",
+        Capability::FeeSwapSwitch,
+        NOT_CALLED,
+    ),
+    ("setCooldownEnabled(bool)", Capability::TradingSwitch, TRAP),
+];
+
+/// A privileged function found in the token's code
+#[derive(Debug, Clone, Serialize)]
+pub struct RiskyFunction {
+    pub signature: &'static str,
+    pub selector: FixedBytes<4>,
+    /// What the function lets its caller do
+    pub capability: Capability,
+    /// Calls made by the owner actions, none if the function cannot trap holders
+    #[serde(skip)]
+    pub owner_calls: &'static [OwnerCall],
+}
+
+/// Every risky function this tool knows of
+pub fn known_functions() -> impl Iterator<Item = RiskyFunction> {
+    RISKY_FUNCTIONS
+        .iter()
+        .map(|&(signature, capability, owner_calls)| RiskyFunction {
+            signature,
+            selector: FixedBytes::from_slice(&keccak256(signature)[..4]),
+            capability,
+            owner_calls,
+        })
+}
+
+/// Returns the known risky functions the token's code dispatches to.
+/// Only the token's own code is read, the functions of a proxy's implementation are not found.
+pub async fn find_risky_functions(
+    token: Address,
+    block_number: u64,
+    client: &AlloyProvider,
+) -> Result<Vec<RiskyFunction>> {
+    let code = client
+        .get_code_at(token)
+        .number(block_number)
+        .await
+        .map_err(HPError::rpc_error)?;
+    let selectors = dispatched_selectors(&code);
+
+    Ok(known_functions()
+        .filter(|function| selectors.contains(&function.selector))
+        .collect())
+}
+
+/// Extracts the selectors of the function dispatcher, which compares the calldata's selector
+/// with every function's one: `PUSH4 <selector> EQ`, or `PUSH4 <selector> DUP2 EQ` with via-IR.
+/// The optimizer drops the leading zero bytes of a selector, e.g. `0x00fdd58e` is pushed with
+/// `PUSH3 fdd58e`, so shorter pushes are read as selectors too.
+fn dispatched_selectors(code: &[u8]) -> Vec<FixedBytes<4>> {
+    let mut selectors = Vec::new();
+    let mut pc = 0;
+
+    while pc < code.len() {
+        let opcode = code[pc];
+
+        if let PUSH1..=PUSH4 = opcode {
+            let size = (opcode - PUSH1) as usize + 1;
+            let end = pc + 1 + size;
+            let compared =
+                code.get(end) == Some(&EQ) || code.get(end..end + 2) == Some(&[DUP2, EQ][..]);
+
+            if let (true, Some(pushed)) = (compared, code.get(pc + 1..end)) {
+                let mut selector = [0; 4];
+                selector[4 - size..].copy_from_slice(pushed);
+                selectors.push(FixedBytes::from(selector));
+            }
+        }
+
+        // Skip the pushed bytes, they are data and not opcodes
+        pc += match opcode {
+            PUSH1..=PUSH32 => (opcode - PUSH1) as usize + 2,
+            _ => 1,
+        };
+    }
+
+    selectors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::hex;
+    use revm::interpreter::opcode::{CALLDATALOAD, JUMPI, PUSH2, PUSH3, SHR};
+
+    /// `selector == <pushed>` followed by the jump to the function, as solc emits it
+    fn dispatch(push: u8, pushed: &[u8], via_ir: bool) -> Vec<u8> {
+        let mut code = vec![push];
+        code.extend_from_slice(pushed);
+        if via_ir {
+            code.push(DUP2);
+        }
+        code.extend_from_slice(&[EQ, PUSH2, 0x01, 0x23, JUMPI]);
+        code
+    }
+
+    #[test]
+    fn legacy_dispatcher() {
+        let code = [
+            // The selector shifted out of the calldata
+            &[PUSH1, 0x00, CALLDATALOAD, PUSH1, 0xe0, SHR][..],
+            &dispatch(PUSH4, &hex!("a9059cbb"), false),
+            &dispatch(PUSH4, &hex!("70a08231"), false),
+        ]
+        .concat();
+
+        assert_eq!(
+            dispatched_selectors(&code),
+            vec![
+                FixedBytes::from(hex!("a9059cbb")),
+                FixedBytes::from(hex!("70a08231"))
+            ]
+        );
+    }
+
+    #[test]
+    fn via_ir_dispatcher() {
+        let code = dispatch(PUSH4, &hex!("095ea7b3"), true);
+
+        assert_eq!(
+            dispatched_selectors(&code),
+            vec![FixedBytes::from(hex!("095ea7b3"))]
+        );
+    }
+
+    #[test]
+    fn selector_with_leading_zero() {
+        let code = [
+            dispatch(PUSH3, &hex!("fdd58e"), false),
+            dispatch(PUSH3, &hex!("fdd58f"), true),
+        ]
+        .concat();
+
+        assert_eq!(
+            dispatched_selectors(&code),
+            vec![
+                FixedBytes::from(hex!("00fdd58e")),
+                FixedBytes::from(hex!("00fdd58f"))
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_pushed_data_and_other_comparisons() {
+        let code = [
+            // A PUSH32 whose data looks like a dispatch
+            &[PUSH32][..],
+            &[PUSH4, 0xa9, 0x05, 0x9c, 0xbb, EQ],
+            &[0; 26],
+            // A pushed selector that is not compared
+            &[PUSH4, 0x70, 0xa0, 0x82, 0x31, JUMPI],
+        ]
+        .concat();
+
+        assert!(dispatched_selectors(&code).is_empty());
+    }
+}
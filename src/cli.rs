@@ -1,14 +1,20 @@
 use alloy::{
+    eips::BlockId,
+    primitives::utils::{parse_units, ParseUnits},
     providers::{Provider, ProviderBuilder},
+    rpc::types::BlockTransactionsKind,
     transports::http::reqwest::Url,
 };
 use anyhow::Result;
 use clap::Parser;
-use revm::primitives::{address, Address};
+use revm::primitives::{address, Address, BlockEnv, U256};
+use serde::Serialize;
+use std::fmt::Display;
 
 use crate::{
-    erc20::{get_erc20_info, ERC20, WETH},
-    error::HPError,
+    erc20::{get_erc20_info, BalanceSlot, ERC20, WETH},
+    error::{ErrorKind, HPError},
+    fork::{block_env, Fork},
     AlloyProvider,
 };
 
@@ -16,8 +22,51 @@ pub const DEFAULT_ACC: Address = address!("e4A6aD6E1B86AB8f2d2f571717592De46bFaF
 
 #[derive(clap::ValueEnum, Clone, Debug, Copy)]
 pub enum Protocol {
+    /// Test every pool of the token that has liquidity
+    Auto,
+    /// Uniswap V2 pair
     UniV2,
+    /// Uniswap V3 pool with the deepest liquidity
     UniV3,
+    /// SushiSwap pair
+    SushiV2,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable report
+    Text,
+    /// The full report as a single line of JSON
+    Json,
+    /// The full report as indented JSON
+    Pretty,
+}
+
+/// How far the chain is moved forward before retrying the sell
+#[derive(clap::ValueEnum, Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum TimeStep {
+    /// The next block
+    Block,
+    /// One minute later
+    Minute,
+    /// One hour later
+    Hour,
+    /// One day later
+    Day,
+}
+
+impl TimeStep {
+    /// Blocks and seconds elapsed after the step, with the 12 seconds block time of mainnet
+    pub fn offset(&self) -> (u64, u64) {
+        let seconds = match self {
+            TimeStep::Block => 12,
+            TimeStep::Minute => 60,
+            TimeStep::Hour => 60 * 60,
+            TimeStep::Day => 24 * 60 * 60,
+        };
+
+        (seconds / 12, seconds)
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -46,11 +95,56 @@ pub struct Cli {
     rpc_url: String,
 
     /// The protocol used to test the token
-    #[arg(short, long, value_enum, default_value_t = Protocol::UniV2)]
+    #[arg(short, long, value_enum, default_value_t = Protocol::Auto)]
     protocol: Protocol,
+
+    /// Block to fork from: a number, a hash or a tag (latest, safe, finalized)
+    #[arg(short, long, default_value = "latest")]
+    block: String,
+
+    /// Format of the report printed to stdout
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Token the tested token is bought with and sold for, e.g. USDC for a stablecoin pair.
+    /// WETH by default
+    #[arg(short, long)]
+    quote_token: Option<String>,
+
+    /// Amount of the quote token the token is bought with, e.g. 0.1
+    #[arg(short, long, default_value = "0.1")]
+    amount: String,
+
+    /// Swap on V2 pools through the router with native ETH like wallets do, instead of with the pair.
+    /// V3 pools are always swapped through the router
+    #[arg(long, default_value_t = false)]
+    via_router: bool,
+
+    /// Also sell 1%, 10%, 50% and 100% of the bought tokens, each on its own copy of the fork
+    #[arg(long, default_value_t = false)]
+    ladder: bool,
+
+    /// Run the round trip from the sender, a fresh wallet, the token owner and a contract,
+    /// to find tokens whitelisting some of them. The verdict is the one of the sender and the fresh wallet
+    #[arg(long, default_value_t = false)]
+    multi_sender: bool,
+
+    /// Also search the largest buy and sell that succeed, to find max transaction and max wallet limits
+    #[arg(long, default_value_t = false)]
+    limits: bool,
+
+    /// Also call the token's privileged functions as the owner with extreme values and sell again,
+    /// to find the switches that would turn the token into a honeypot
+    #[arg(long, default_value_t = false)]
+    owner_actions: bool,
+
+    /// Retry the sell after moving the chain forward by each of these steps,
+    /// e.g. `block,minute,hour,day`
+    #[arg(short, long, value_enum, value_delimiter = ',')]
+    time_travel: Vec<TimeStep>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CliConfig {
     pub from_token: ERC20,
     pub token: ERC20,
@@ -58,6 +152,34 @@ pub struct CliConfig {
     pub sender: Address,
     pub client: AlloyProvider,
     pub protocol: Protocol,
+    pub output: OutputFormat,
+    /// Block the simulations are forked from
+    pub block_number: u64,
+    /// Environment of the forked block, so simulations see the same block as the chain did
+    pub block_env: BlockEnv,
+    /// Amount of the quote token the token is bought with, in its smallest unit
+    pub amount: U256,
+    /// Where the quote token stores balances, to fund the sender
+    pub quote_balance_slot: BalanceSlot,
+    pub via_router: bool,
+    pub ladder: bool,
+    pub limits: bool,
+    pub multi_sender: bool,
+    /// Steps the sell is retried at, sorted and without duplicates
+    pub time_travel: Vec<TimeStep>,
+    pub owner_actions: bool,
+}
+
+impl CliConfig {
+    /// Prints a line besides the report, e.g. a `--logs` one. With JSON output,
+    /// stdout only holds the report, so the line goes to stderr.
+    pub fn log(&self, line: impl Display) {
+        if self.output == OutputFormat::Text {
+            println!("{line}");
+        } else {
+            eprintln!("{line}");
+        }
+    }
 }
 
 impl Cli {
@@ -70,27 +192,76 @@ impl Cli {
             DEFAULT_ACC
         };
 
-        let rpc_url = Url::parse(&self.rpc_url).map_err(|e| HPError::new(e.to_string(), None))?;
+        let rpc_url =
+            Url::parse(&self.rpc_url).map_err(|e| HPError::invalid_input(e.to_string()))?;
         let client = ProviderBuilder::new().on_http(rpc_url.clone());
 
         let chain_id = client.get_chain_id().await.map_err(HPError::rpc_error)?;
         if chain_id != 1 {
-            return Err(HPError::err_msg(format!(
+            return Err(HPError::invalid_input(format!(
                 "Only mainnet is supported, the provided RPC URL is for chain {}",
                 chain_id
             )));
         }
 
-        let from_token = ERC20 {
-            address: WETH,
-            name: "Wrapped Ether".to_string(),
-            symbol: "WETH".to_string(),
-            decimals: 18,
-        };
+        let block_id: BlockId = self.block.parse().map_err(|e| {
+            HPError::invalid_input(format!("Invalid block '{}': {}", self.block, e))
+        })?;
 
-        let token = get_erc20_info(&token, &client)
+        // Resolve the block once, so every venue is tested against the same state
+        let block = client
+            .get_block(block_id, BlockTransactionsKind::Hashes)
             .await
-            .map_err(HPError::rpc_error)?;
+            .map_err(HPError::rpc_error)?
+            .ok_or_else(|| HPError::invalid_input(format!("Block '{}' not found", self.block)))?;
+
+        let block_number = block.header.number;
+        let block_env = block_env(&block.header);
+
+        let from_token = if let Some(quote_token) = self.quote_token.as_ref() {
+            let quote_token: Address = quote_token.parse().map_err(HPError::parse_error)?;
+            get_erc20_info(&quote_token, block_number, &client).await?
+        } else {
+            ERC20 {
+                address: WETH,
+                name: "Wrapped Ether".to_string(),
+                symbol: "WETH".to_string(),
+                decimals: 18,
+            }
+        };
+
+        let token = get_erc20_info(&token, block_number, &client).await?;
+        if token.address == from_token.address {
+            return Err(HPError::invalid_input(
+                "The token and the quote token must be different".to_string(),
+            ));
+        }
+
+        let amount = match parse_units(&self.amount, from_token.decimals) {
+            Ok(ParseUnits::U256(amount)) if !amount.is_zero() => amount,
+            _ => {
+                return Err(HPError::invalid_input(format!(
+                    "Invalid amount '{}', expected a positive number of {}",
+                    self.amount, from_token.symbol
+                )))
+            }
+        };
+
+        // Found once, as the search reads up to 200 storage slots on every fresh fork
+        let mut fork = Fork::at(&client, block_number, block_env.clone())?;
+        let quote_balance_slot = from_token
+            .find_balance_slot(sender, &mut fork)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnsupportedToken => HPError::invalid_input(format!(
+                    "Unsupported quote token, the sender cannot be funded: {}",
+                    e
+                )),
+                _ => e,
+            })?;
+
+        let mut time_travel = self.time_travel.clone();
+        time_travel.sort();
+        time_travel.dedup();
 
         Ok(CliConfig {
             from_token,
@@ -99,6 +270,17 @@ impl Cli {
             sender,
             client: client.clone(),
             protocol: self.protocol,
+            output: self.output,
+            block_number,
+            block_env,
+            amount,
+            quote_balance_slot,
+            via_router: self.via_router,
+            ladder: self.ladder,
+            limits: self.limits,
+            multi_sender: self.multi_sender,
+            time_travel,
+            owner_actions: self.owner_actions,
         })
     }
 }
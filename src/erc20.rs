@@ -1,19 +1,28 @@
 use alloy::{
-    primitives::Address,
+    eips::BlockId,
+    primitives::{keccak256, Address},
     providers::{Provider, RootProvider},
     rpc::types::TransactionRequest,
     sol,
     sol_types::{SolCall, SolValue},
-    transports::http::{Client, Http},
+    transports::{
+        http::{Client, Http},
+        TransportError,
+    },
 };
+use serde::Serialize;
+use std::fmt::Display;
+
 use revm::{
-    primitives::{address, ExecutionResult, Output, TxKind, U256},
-    Evm,
+    inspector_handle_register,
+    primitives::{address, Bytes, ExecutionResult, Output, TxKind, U256},
+    Database, Evm,
 };
 
 use crate::{
-    error::{HPError, Result},
-    AlloyCacheDB,
+    error::{ErrorKind, HPError, Result},
+    fork::Fork,
+    trace::CallTracer,
 };
 
 pub const WETH: Address = address!("c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
@@ -21,9 +30,15 @@ pub const WETH: Address = address!("c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
 sol! {
     function balanceOf(address account) public returns (uint256);
     function transfer(address to, uint amount) external returns (bool);
+    function approve(address spender, uint256 amount) external returns (bool);
+    function allowance(address owner, address spender) external view returns (uint256);
+    function transferFrom(address from, address to, uint256 amount) external returns (bool);
+    function totalSupply() external view returns (uint256);
+    function owner() external view returns (address);
+    function getOwner() external view returns (address);
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ERC20 {
     pub address: Address,
     pub name: String,
@@ -31,7 +46,62 @@ pub struct ERC20 {
     pub decimals: u8,
 }
 
-pub async fn get_erc20_info(token: &Address, client: &RootProvider<Http<Client>>) -> Result<ERC20> {
+/// Highest storage slot probed for the balance mapping
+const MAX_BALANCE_SLOT: u64 = 100;
+
+/// Gives `account` `amount` of ETH, e.g. to pay for swaps from ETH.
+pub fn fund_eth(account: Address, amount: U256, fork: &mut Fork) -> Result<()> {
+    let mut info = fork
+        .db
+        .basic(account)
+        .map_err(HPError::rpc_error)?
+        .unwrap_or_default();
+    info.balance = amount;
+    fork.db.insert_account_info(account, info);
+
+    Ok(())
+}
+
+pub fn eth_balance(account: Address, fork: &mut Fork) -> Result<U256> {
+    let info = fork.db.basic(account).map_err(HPError::rpc_error)?;
+
+    Ok(info.map(|info| info.balance).unwrap_or_default())
+}
+
+/// How the compiler lays out the entries of a mapping in storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingLayout {
+    /// `keccak256(key . slot)`
+    Solidity,
+    /// `keccak256(slot . key)`
+    Vyper,
+}
+
+/// Storage slot of the `balanceOf` mapping of a token
+#[derive(Debug, Clone, Copy)]
+pub struct BalanceSlot {
+    pub slot: U256,
+    pub layout: MappingLayout,
+}
+
+impl BalanceSlot {
+    /// Storage slot holding the balance of `account`
+    pub fn of(&self, account: Address) -> U256 {
+        let key = match self.layout {
+            MappingLayout::Solidity => keccak256((account, self.slot).abi_encode()),
+            MappingLayout::Vyper => keccak256((self.slot, account).abi_encode()),
+        };
+
+        key.into()
+    }
+}
+
+/// Reads the name, symbol and decimals of the token at the block.
+pub async fn get_erc20_info(
+    token: &Address,
+    block_number: u64,
+    client: &RootProvider<Http<Client>>,
+) -> Result<ERC20> {
     sol! {
       function name() public view returns (string);
       function symbol() public view returns (string);
@@ -48,37 +118,52 @@ pub async fn get_erc20_info(token: &Address, client: &RootProvider<Http<Client>>
             input: name.into(),
             ..Default::default()
         })
+        .block(BlockId::number(block_number))
         .await
-        .expect("Token isn't ERC20 token");
+        .map_err(call_error)?;
     let symbol = client
         .call(&TransactionRequest {
             to: Some(TxKind::Call(*token)),
             input: symbol.into(),
             ..Default::default()
         })
+        .block(BlockId::number(block_number))
         .await
-        .expect("Token isn't ERC20 token");
+        .map_err(call_error)?;
     let decimals = client
         .call(&TransactionRequest {
             to: Some(TxKind::Call(*token)),
             input: decimals.into(),
             ..Default::default()
         })
+        .block(BlockId::number(block_number))
         .await
-        .expect("Token isn't ERC20 token");
+        .map_err(call_error)?;
 
-    let name = nameCall::abi_decode_returns(&name, false).map_err(HPError::error)?;
-    let symbol = symbolCall::abi_decode_returns(&symbol, false).map_err(HPError::error)?;
-    let decimals = decimalsCall::abi_decode_returns(&decimals, false).map_err(HPError::error)?;
+    let name = nameCall::abi_decode_returns(&name, false).map_err(not_erc20)?;
+    let symbol = symbolCall::abi_decode_returns(&symbol, false).map_err(not_erc20)?;
+    let decimals = decimalsCall::abi_decode_returns(&decimals, false).map_err(not_erc20)?;
 
     Ok(ERC20 {
-        address: token.clone(),
+        address: *token,
         name: name._0,
         symbol: symbol._0,
         decimals: decimals._0,
     })
 }
 
+/// A reverted call means the address is not an ERC20 token, any other error comes from the RPC endpoint.
+fn call_error(e: TransportError) -> HPError {
+    match e.as_error_resp() {
+        Some(_) => not_erc20(e),
+        None => HPError::rpc_error(e),
+    }
+}
+
+fn not_erc20(e: impl Display) -> HPError {
+    HPError::invalid_input(format!("Token isn't ERC20 token: {e}"))
+}
+
 impl ERC20 {
     // pub fn new(address: Address, name: String, symbol: String, decimals: u8) -> Self {
     //     Self {
@@ -89,16 +174,142 @@ impl ERC20 {
     //     }
     // }
 
-    pub fn balance_of(
+    /// Finds the `balanceOf` mapping by writing a marker value to every candidate slot
+    /// and checking whether `balanceOf` returns it. Every probed slot is restored.
+    pub fn find_balance_slot(&self, account: Address, fork: &mut Fork) -> Result<BalanceSlot> {
+        let marker = U256::from(0x1234_5678_9abc_u64);
+
+        for slot in 0..MAX_BALANCE_SLOT {
+            for layout in [MappingLayout::Solidity, MappingLayout::Vyper] {
+                let candidate = BalanceSlot {
+                    slot: U256::from(slot),
+                    layout,
+                };
+                let key = candidate.of(account);
+
+                let original = fork
+                    .db
+                    .storage(self.address, key)
+                    .map_err(HPError::rpc_error)?;
+                fork.db
+                    .insert_account_storage(self.address, key, marker)
+                    .map_err(HPError::rpc_error)?;
+                let balance = match self.balance_of(account, account, fork) {
+                    Ok(balance) => Some(balance),
+                    // A token reverting on a corrupted slot is just not using this slot
+                    Err(e) if e.kind() == ErrorKind::Execution => None,
+                    Err(e) => return Err(e),
+                };
+                fork.db
+                    .insert_account_storage(self.address, key, original)
+                    .map_err(HPError::rpc_error)?;
+
+                if balance == Some(marker) {
+                    return Ok(candidate);
+                }
+            }
+        }
+
+        Err(HPError::unsupported_token(format!(
+            "Could not find the balance slot of {} in slots 0 to {}",
+            self.symbol,
+            MAX_BALANCE_SLOT - 1
+        )))
+    }
+
+    /// Sets the balance of `account` to `amount` by writing the token's storage directly,
+    /// in a balance mapping found with `find_balance_slot`.
+    pub fn set_balance(
+        &self,
+        balance_slot: BalanceSlot,
+        account: Address,
+        amount: U256,
+        fork: &mut Fork,
+    ) -> Result<()> {
+        fork.db
+            .insert_account_storage(self.address, balance_slot.of(account), amount)
+            .map_err(HPError::rpc_error)
+    }
+
+    pub fn total_supply(&self, sender: Address, fork: &mut Fork) -> Result<U256> {
+        let total_supply =
+            self.view(totalSupplyCall {}.abi_encode(), "totalSupply", sender, fork)?;
+
+        <U256>::abi_decode(&total_supply, false).map_err(HPError::error)
+    }
+
+    /// Returns the owner of the token from `owner()` or `getOwner()`,
+    /// `None` if it has neither or the ownership was renounced.
+    pub fn owner(&self, sender: Address, fork: &mut Fork) -> Result<Option<Address>> {
+        for (calldata, function) in [
+            (ownerCall {}.abi_encode(), "owner"),
+            (getOwnerCall {}.abi_encode(), "getOwner"),
+        ] {
+            let output = match self.view(calldata, function, sender, fork) {
+                Ok(output) => output,
+                Err(e) if e.kind() == ErrorKind::Execution => continue,
+                Err(e) => return Err(e),
+            };
+
+            if let Ok(owner) = <Address>::abi_decode(&output, false) {
+                return Ok((owner != Address::ZERO).then_some(owner));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn allowance(
         &self,
         owner: Address,
+        spender: Address,
         sender: Address,
-        alloy_db: &mut AlloyCacheDB,
+        fork: &mut Fork,
     ) -> Result<U256> {
+        let calldata = allowanceCall { owner, spender }.abi_encode();
+        let allowance = self.view(calldata, "allowance", sender, fork)?;
+
+        <U256>::abi_decode(&allowance, false).map_err(HPError::error)
+    }
+
+    /// Calls a view function of the token without committing anything and returns its output.
+    fn view(
+        &self,
+        calldata: Vec<u8>,
+        function: &str,
+        sender: Address,
+        fork: &mut Fork,
+    ) -> Result<Bytes> {
+        let mut evm = Evm::builder()
+            .with_db(&mut fork.db)
+            .with_env(fork.env.clone())
+            .modify_tx_env(|tx| {
+                tx.caller = sender;
+                tx.transact_to = TxKind::Call(self.address);
+                tx.data = calldata.into();
+            })
+            .build();
+
+        let tx = evm.transact().map_err(HPError::evm_error)?;
+
+        match tx.result {
+            ExecutionResult::Success {
+                output: Output::Call(value),
+                ..
+            } => Ok(value),
+            result => Err(HPError::execution_failed(
+                &format!("'{function}' execution failed"),
+                &result,
+            )),
+        }
+    }
+
+    pub fn balance_of(&self, owner: Address, sender: Address, fork: &mut Fork) -> Result<U256> {
         let encoded = balanceOfCall { account: owner }.abi_encode();
 
         let mut evm = Evm::builder()
-            .with_db(alloy_db)
+            .with_db(&mut fork.db)
+            .with_env(fork.env.clone())
             .modify_tx_env(|tx| {
                 // For consistency, we use the same sender for all calls
                 tx.caller = sender;
@@ -108,7 +319,7 @@ impl ERC20 {
             })
             .build();
 
-        let tx = evm.transact().map_err(HPError::error)?;
+        let tx = evm.transact().map_err(HPError::evm_error)?;
 
         let result = tx.result;
         let balance = match result {
@@ -117,9 +328,9 @@ impl ERC20 {
                 ..
             } => value,
             result => {
-                return Err(HPError::new(
-                    format!("'balanceOf' execution failed: {result:?}"),
-                    None,
+                return Err(HPError::execution_failed(
+                    "'balanceOf' execution failed",
+                    &result,
                 ))
             }
         };
@@ -129,54 +340,165 @@ impl ERC20 {
         Ok(balance)
     }
 
+    /// Transfers `amount` from `from` to `to` and returns the gas used.
     pub fn transfer(
         &self,
         from: Address,
         to: Address,
         amount: U256,
         // token: Address,
-        alloy_db: &mut AlloyCacheDB,
-    ) -> Result<()> {
+        fork: &mut Fork,
+    ) -> Result<u64> {
         let calldata = transferCall { to, amount }.abi_encode();
 
         let mut evm = Evm::builder()
-            .with_db(alloy_db)
+            .with_db(&mut fork.db)
+            .with_env(fork.env.clone())
+            .with_external_context(CallTracer::default())
             .modify_tx_env(|tx| {
                 tx.caller = from;
                 tx.transact_to = TxKind::Call(self.address);
                 tx.data = calldata.into();
                 tx.value = U256::from(0);
             })
+            .append_handler_register(inspector_handle_register)
             .build();
 
-        let tx = evm.transact_commit().map_err(HPError::error)?;
+        let tx = evm.transact_commit().map_err(HPError::evm_error)?;
 
-        let res = match tx {
+        let (res, gas_used) = match tx {
             ExecutionResult::Success {
                 output: Output::Call(value),
+                gas_used,
                 ..
-            } => value,
+            } => (value, gas_used),
             result => {
-                return Err(HPError::new(
-                    format!("'transfer' execution failed: {result:?}"),
-                    None,
-                ))
+                return Err(
+                    HPError::execution_failed("'transfer' execution failed", &result)
+                        .with_trace(&evm.context.external),
+                )
             }
         };
 
         // Some tokens do not return a boolean, so we check if the result is empty
         // If it is empty, we consider the transfer successful, because if it would have failed,
         // the transaction would have failed.
-        if res.len() == 0 {
+        if res.is_empty() {
+            return Ok(gas_used);
+        }
+
+        let is_success = <bool>::abi_decode(&res, false).map_err(HPError::error)?;
+
+        if !is_success {
+            return Err(HPError::execution("'transfer' failed".to_string()));
+        }
+
+        Ok(gas_used)
+    }
+
+    pub fn approve(
+        &self,
+        owner: Address,
+        spender: Address,
+        amount: U256,
+        fork: &mut Fork,
+    ) -> Result<()> {
+        let calldata = approveCall { spender, amount }.abi_encode();
+
+        let mut evm = Evm::builder()
+            .with_db(&mut fork.db)
+            .with_env(fork.env.clone())
+            .with_external_context(CallTracer::default())
+            .modify_tx_env(|tx| {
+                tx.caller = owner;
+                tx.transact_to = TxKind::Call(self.address);
+                tx.data = calldata.into();
+                tx.value = U256::from(0);
+            })
+            .append_handler_register(inspector_handle_register)
+            .build();
+
+        let tx = evm.transact_commit().map_err(HPError::evm_error)?;
+
+        let res = match tx {
+            ExecutionResult::Success {
+                output: Output::Call(value),
+                ..
+            } => value,
+            result => {
+                return Err(
+                    HPError::execution_failed("'approve' execution failed", &result)
+                        .with_trace(&evm.context.external),
+                )
+            }
+        };
+
+        // Same as `transfer`, tokens like USDT do not return a boolean
+        if res.is_empty() {
             return Ok(());
         }
 
         let is_success = <bool>::abi_decode(&res, false).map_err(HPError::error)?;
 
         if !is_success {
-            return Err(HPError::new("'transfer' failed".to_string(), None));
+            return Err(HPError::execution("'approve' failed".to_string()));
         }
 
         Ok(())
     }
+
+    /// Transfers `amount` from `from` to `to` as `spender`, using its allowance.
+    /// Returns the gas used.
+    pub fn transfer_from(
+        &self,
+        spender: Address,
+        from: Address,
+        to: Address,
+        amount: U256,
+        fork: &mut Fork,
+    ) -> Result<u64> {
+        let calldata = transferFromCall { from, to, amount }.abi_encode();
+
+        let mut evm = Evm::builder()
+            .with_db(&mut fork.db)
+            .with_env(fork.env.clone())
+            .with_external_context(CallTracer::default())
+            .modify_tx_env(|tx| {
+                tx.caller = spender;
+                tx.transact_to = TxKind::Call(self.address);
+                tx.data = calldata.into();
+                tx.value = U256::from(0);
+            })
+            .append_handler_register(inspector_handle_register)
+            .build();
+
+        let tx = evm.transact_commit().map_err(HPError::evm_error)?;
+
+        let (res, gas_used) = match tx {
+            ExecutionResult::Success {
+                output: Output::Call(value),
+                gas_used,
+                ..
+            } => (value, gas_used),
+            result => {
+                return Err(
+                    HPError::execution_failed("'transferFrom' execution failed", &result)
+                        .with_trace(&evm.context.external),
+                )
+            }
+        };
+
+        // Same as `transfer`, some tokens do not return a boolean
+        if res.is_empty() {
+            return Ok(gas_used);
+        }
+
+        let is_success = <bool>::abi_decode(&res, false).map_err(HPError::error)?;
+
+        if !is_success {
+            return Err(HPError::execution("'transferFrom' failed".to_string()));
+        }
+
+        Ok(gas_used)
+    }
 }
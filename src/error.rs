@@ -1,37 +1,130 @@
 use alloy::hex::FromHexError;
 use anyhow::Result as AnyhowResult;
+use revm::primitives::{EVMError, ExecutionResult};
 use std::error::Error;
 use std::fmt::{Debug, Display};
 
+use crate::{revert::ExecutionFailure, trace::CallTracer};
+
 pub type Result<T> = AnyhowResult<T, HPError>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A simulated transaction reverted or halted
+    Execution,
+    /// The token has no pool to be tested on
+    NoLiquidity,
+    /// The RPC endpoint failed or returned an error
+    Rpc,
+    /// The arguments passed to the CLI are invalid
+    InvalidInput,
+    /// The token does not fit what the simulation assumes, e.g. its balances were not found in storage
+    UnsupportedToken,
+    Other,
+}
+
+impl ErrorKind {
+    /// Process exit code of the error, see `Verdict::exit_code` for the codes of a finished test
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ErrorKind::NoLiquidity => 3,
+            ErrorKind::Execution
+            | ErrorKind::Rpc
+            | ErrorKind::UnsupportedToken
+            | ErrorKind::Other => 4,
+            ErrorKind::InvalidInput => 5,
+        }
+    }
+}
+
 pub struct HPError {
     message: String,
-    is_honeypot: Option<bool>,
+    kind: ErrorKind,
+    /// Boxed, as the call trace would make every `Result` of the crate large
+    failure: Option<Box<ExecutionFailure>>,
 }
 
 impl HPError {
-    pub fn new(message: String, is_honeypot: Option<bool>) -> Self {
+    pub fn new(message: String) -> Self {
+        Self::with_kind(message, ErrorKind::Other)
+    }
+
+    pub fn with_kind(message: String, kind: ErrorKind) -> Self {
         Self {
             message,
-            is_honeypot,
+            kind,
+            failure: None,
+        }
+    }
+
+    /// The simulated transaction did not succeed, which is what the test is looking for
+    pub fn execution(message: String) -> Self {
+        Self::with_kind(message, ErrorKind::Execution)
+    }
+
+    /// The simulated transaction reverted or halted, the reason is decoded from `result`
+    pub fn execution_failed(message: &str, result: &ExecutionResult) -> Self {
+        let failure = ExecutionFailure::from_result(result);
+
+        Self {
+            message: format!("{message}: {failure}"),
+            kind: ErrorKind::Execution,
+            failure: Some(Box::new(failure)),
         }
     }
 
-    pub fn err_msg(message: String) -> Self {
-        Self::new(message, None)
+    /// Adds the calls recorded during the failed transaction
+    pub fn with_trace(mut self, tracer: &CallTracer) -> Self {
+        self.failure = self
+            .failure
+            .map(|failure| Box::new(failure.with_trace(tracer)));
+        self
+    }
+
+    /// Prefixes the message with where the error happened, e.g. the hop of a route
+    pub fn context(mut self, context: impl Display) -> Self {
+        self.message = format!("{context}: {}", self.message);
+        self
+    }
+
+    pub fn no_liquidity(message: String) -> Self {
+        Self::with_kind(message, ErrorKind::NoLiquidity)
+    }
+
+    pub fn invalid_input(message: String) -> Self {
+        Self::with_kind(message, ErrorKind::InvalidInput)
+    }
+
+    pub fn unsupported_token(message: String) -> Self {
+        Self::with_kind(message, ErrorKind::UnsupportedToken)
     }
 
     pub fn parse_error(e: FromHexError) -> Self {
-        Self::new(e.to_string(), None)
+        Self::invalid_input(e.to_string())
     }
 
     pub fn rpc_error(e: impl Display) -> Self {
-        Self::new(e.to_string(), None)
+        Self::with_kind(e.to_string(), ErrorKind::Rpc)
     }
 
     pub fn error(e: impl Error) -> Self {
-        Self::new(e.to_string(), None)
+        Self::new(e.to_string())
+    }
+
+    /// Revm fetches the state it lacks from the RPC endpoint, so its database errors are RPC errors
+    pub fn evm_error<DB: Display>(e: EVMError<DB>) -> Self {
+        match e {
+            EVMError::Database(e) => Self::rpc_error(e),
+            e => Self::new(e.to_string()),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn failure(&self) -> Option<&ExecutionFailure> {
+        self.failure.as_deref()
     }
 }
 
@@ -45,8 +138,8 @@ impl Debug for HPError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "HPError {{ message: {}, is_honeypot: {:?} }}",
-            self.message, self.is_honeypot
+            "HPError {{ message: {}, kind: {:?}, failure: {:?} }}",
+            self.message, self.kind, self.failure
         )
     }
 }
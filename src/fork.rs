@@ -0,0 +1,84 @@
+use alloy::{eips::BlockId, rpc::types::Header};
+use revm::{
+    db::{AlloyDB, CacheDB},
+    primitives::{BlockEnv, Env, U256},
+};
+use std::sync::Arc;
+
+use crate::{
+    cli::CliConfig,
+    error::{HPError, Result},
+    AlloyCacheDB, AlloyProvider,
+};
+
+/// Chain state the simulations run on, forked from the tested block.
+/// Cloning it forks the simulation, the remote state is shared.
+#[derive(Clone)]
+pub struct Fork {
+    pub db: AlloyCacheDB,
+    /// Block and chain configuration used by every simulated transaction
+    pub env: Box<Env>,
+}
+
+impl Fork {
+    pub fn new(config: &CliConfig) -> Result<Self> {
+        Self::at(
+            &config.client,
+            config.block_number,
+            config.block_env.clone(),
+        )
+    }
+
+    /// Forks the state at `block_number`, whose environment is `block_env`.
+    pub fn at(client: &AlloyProvider, block_number: u64, block_env: BlockEnv) -> Result<Self> {
+        let db = AlloyDB::new(client.clone(), BlockId::number(block_number))
+            .ok_or_else(|| HPError::new("Failed to create Revm Alloy DB".to_string()))?;
+
+        let gas_limit = block_env.gas_limit.saturating_to();
+        let mut env = Env {
+            block: block_env,
+            ..Default::default()
+        };
+        // Transactions are simulated with a zero gas price like `eth_call`,
+        // so the sender does not need ETH for gas
+        env.cfg.disable_base_fee = true;
+        // Like `eth_call`, let contracts send transactions, to test contract senders
+        env.cfg.disable_eip3607 = true;
+        // The default gas limit of a transaction is above any block gas limit
+        env.tx.gas_limit = gas_limit;
+
+        Ok(Self {
+            db: CacheDB::new(Arc::new(db)),
+            env: Box::new(env),
+        })
+    }
+
+    /// Moves the simulated chain `blocks` blocks and `seconds` seconds forward.
+    /// The state stays the one of the forked block.
+    pub fn advance(&mut self, blocks: u64, seconds: u64) {
+        self.env.block.number += U256::from(blocks);
+        self.env.block.timestamp += U256::from(seconds);
+    }
+}
+
+/// Builds the environment of the block from its header.
+/// Tokens may read any of these fields, e.g. to block trades in the launch block
+/// or to only let the block builder buy.
+pub fn block_env(header: &Header) -> BlockEnv {
+    let mut block_env = BlockEnv {
+        number: U256::from(header.number),
+        coinbase: header.miner,
+        timestamp: U256::from(header.timestamp),
+        gas_limit: U256::from(header.gas_limit),
+        basefee: U256::from(header.base_fee_per_gas.unwrap_or_default()),
+        difficulty: header.difficulty,
+        // Since the merge, `mix_hash` holds the randomness returned by `block.prevrandao`
+        prevrandao: Some(header.mix_hash.unwrap_or_default()),
+        ..Default::default()
+    };
+    if let Some(excess_blob_gas) = header.excess_blob_gas {
+        block_env.set_blob_excess_gas_and_price(excess_blob_gas);
+    }
+
+    block_env
+}
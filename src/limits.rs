@@ -0,0 +1,213 @@
+use alloy::primitives::U256;
+use serde::Serialize;
+
+use crate::{
+    cli::CliConfig,
+    erc20::fund_eth,
+    error::{ErrorKind, Result},
+    fork::Fork,
+    test_swap::{swap_and_measure, TestSwap},
+};
+
+/// Largest amount of the quote token tried to buy with, as a multiple of the tested amount
+const MAX_BUY_FACTOR: u64 = 10_000;
+/// The search stops once the largest amount is known within 1/1000 of it
+const PRECISION: u64 = 1_000;
+
+/// Largest trade that succeeded
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TradeLimit {
+    /// Amount of the token bought or sold
+    pub amount: U256,
+    pub percent_of_supply: f64,
+    /// `false` if the largest amount tried succeeded, so no limit was found
+    pub limited: bool,
+}
+
+/// Largest buy and sell the token allows, to find anti-whale limits
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Limits {
+    pub total_supply: U256,
+    /// `None` if not even the tested amount could be traded
+    pub max_buy: Option<TradeLimit>,
+    pub max_sell: Option<TradeLimit>,
+    /// Why a limit could not be searched
+    pub error: Option<String>,
+}
+
+/// Binary searches the largest buy and sell that succeed, each attempt on its own copy of `fork`.
+/// `fork` is the state before the test bought anything, `bought` what the test's buy received.
+pub fn find_limits(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    bought: U256,
+    fork: &mut Fork,
+) -> Result<Limits> {
+    let sender = config.sender;
+    let mut limits = Limits {
+        total_supply: config.token.total_supply(sender, fork)?,
+        ..Default::default()
+    };
+
+    // The quote token balance is set for every attempt, so the search is not capped by the funding
+    let max_buy = search_max(
+        config.amount,
+        config.amount * U256::from(MAX_BUY_FACTOR),
+        |amount| {
+            let mut fork = fork.clone();
+            config
+                .from_token
+                .set_balance(config.quote_balance_slot, sender, amount, &mut fork)?;
+            // Buys through the router pay with ETH
+            fund_eth(sender, amount, &mut fork)?;
+
+            let leg = swap_and_measure(
+                venue,
+                config,
+                &config.from_token,
+                &config.token,
+                amount,
+                &mut fork,
+            );
+            succeeded(leg.map(|leg| leg.received))
+        },
+    )?;
+    limits.max_buy = max_buy.map(|(_, received, limited)| TradeLimit {
+        amount: received,
+        percent_of_supply: percent_of(received, limits.total_supply),
+        limited,
+    });
+
+    // Selling more than was bought needs the token balance to be written directly
+    let token_slot = match config.token.find_balance_slot(sender, fork) {
+        Ok(slot) => slot,
+        Err(e) if e.kind() == ErrorKind::UnsupportedToken => {
+            limits.error = Some(format!("Max sell not searched: {}", e));
+            return Ok(limits);
+        }
+        Err(e) => return Err(e),
+    };
+    let max_sell = search_max(bought, limits.total_supply, |amount| {
+        let mut fork = fork.clone();
+        config
+            .token
+            .set_balance(token_slot, sender, amount, &mut fork)?;
+
+        let leg = swap_and_measure(
+            venue,
+            config,
+            &config.token,
+            &config.from_token,
+            amount,
+            &mut fork,
+        );
+        succeeded(leg.map(|_| ()))
+    })?;
+    limits.max_sell = max_sell.map(|(amount, _, limited)| TradeLimit {
+        amount,
+        percent_of_supply: percent_of(amount, limits.total_supply),
+        limited,
+    });
+
+    Ok(limits)
+}
+
+/// Returns the largest amount between `low` and `high` `attempt` succeeds with,
+/// the outcome of that attempt and whether a larger amount failed.
+/// Assumes every amount below one that succeeds succeeds too.
+fn search_max<T>(
+    mut low: U256,
+    mut high: U256,
+    mut attempt: impl FnMut(U256) -> Result<Option<T>>,
+) -> Result<Option<(U256, T, bool)>> {
+    if let Some(outcome) = attempt(high)? {
+        return Ok(Some((high, outcome, false)));
+    }
+    let Some(mut best) = attempt(low)? else {
+        return Ok(None);
+    };
+
+    while high - low > high / U256::from(PRECISION) {
+        let middle = low + (high - low) / U256::from(2);
+        match attempt(middle)? {
+            Some(outcome) => {
+                low = middle;
+                best = outcome;
+            }
+            None => high = middle,
+        }
+    }
+
+    Ok(Some((low, best, true)))
+}
+
+/// A failed trade is an answer of the search, any other error means the search itself failed.
+fn succeeded<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(outcome) => Ok(Some(outcome)),
+        Err(e) if e.kind() == ErrorKind::Execution => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns `amount` as a percentage of `total`, with two decimals.
+fn percent_of(amount: U256, total: U256) -> f64 {
+    if total.is_zero() {
+        return 0.0;
+    }
+
+    let bps = amount.saturating_mul(U256::from(10_000)) / total;
+    bps.saturating_to::<u64>() as f64 / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Attempt succeeding up to `limit`, returning the amount tried
+    fn up_to(limit: u64) -> impl FnMut(U256) -> Result<Option<U256>> {
+        move |amount| Ok((amount <= U256::from(limit)).then_some(amount))
+    }
+
+    #[test]
+    fn high_succeeds() {
+        let found = search_max(U256::from(10), U256::from(100_000), up_to(u64::MAX)).unwrap();
+
+        assert_eq!(
+            found,
+            Some((U256::from(100_000), U256::from(100_000), false))
+        );
+    }
+
+    #[test]
+    fn low_fails() {
+        let found = search_max(U256::from(10), U256::from(100_000), up_to(5)).unwrap();
+
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn stops_at_precision() {
+        let limit = 12_345;
+        let mut attempts = 0;
+        let found = search_max(U256::from(10), U256::from(100_000), |amount| {
+            attempts += 1;
+            up_to(limit)(amount)
+        })
+        .unwrap();
+
+        let (amount, outcome, limited) = found.unwrap();
+        assert!(limited);
+        assert_eq!(outcome, amount);
+        assert!(amount <= U256::from(limit));
+        // The search ends once the bounds are within 1/1000 of the upper one
+        assert!(U256::from(limit) - amount <= U256::from(100_000 / PRECISION));
+        assert!(attempts < 20);
+    }
+
+    #[test]
+    fn percent_of_supply() {
+        assert_eq!(percent_of(U256::from(1), U256::from(3)), 33.33);
+        assert_eq!(percent_of(U256::from(1), U256::ZERO), 0.0);
+    }
+}
@@ -1,57 +1,174 @@
 use alloy::{
-    eips::BlockId,
     network::Ethereum,
     providers::RootProvider,
     transports::http::{Client, Http},
 };
 use clap::Parser;
-use cli::{Cli, CliConfig, Protocol};
+use cli::{Cli, CliConfig, OutputFormat};
 
 use crate::{
-    error::{HPError, Result},
+    bytecode::{find_risky_functions, RiskyFunction},
+    error::{ErrorKind, HPError, Result},
+    fork::Fork,
+    report::{is_sender_dependent, HoneypotReport, Summary, Verdict},
+    sender::{get_senders, prepare_sender, SenderKind},
     test_swap::TestSwap,
 };
 use revm::db::{AlloyDB, CacheDB};
-use uniswapv2::UniswapV2;
-// use uniswapv3::test_swap;
+use std::{process::ExitCode, sync::Arc};
+use venue::get_venues;
 
+mod bytecode;
 mod cli;
 mod erc20;
 mod error;
+mod fork;
+mod limits;
+mod privilege;
+mod report;
+mod revert;
+mod route;
+mod sender;
 mod test_swap;
+mod trace;
 mod uniswapv2;
 mod uniswapv3;
+mod venue;
 
 type AlloyProvider = RootProvider<Http<Client>>;
-type AlloyCacheDB = CacheDB<AlloyDB<Http<Client>, Ethereum, AlloyProvider>>;
+/// The remote state is shared behind an `Arc`, so the cache can be cloned to fork the simulation
+type AlloyCacheDB = CacheDB<Arc<AlloyDB<Http<Client>, Ethereum, AlloyProvider>>>;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let config = Cli::parse().validate().await?;
+async fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            // `--help` and `--version` are reported as errors by clap, but are not failures
+            return if e.use_stderr() {
+                ExitCode::from(ErrorKind::InvalidInput.exit_code())
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+
+    match run(cli).await {
+        Ok(verdict) => ExitCode::from(verdict.exit_code()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.kind().exit_code())
+        }
+    }
+}
+
+/// Tests the token on every venue and returns the worst verdict
+async fn run(cli: Cli) -> Result<Verdict> {
+    let config = cli.validate().await?;
 
-    let mut cache_db = get_cache_db(config.client.clone())?;
+    if config.logs {
+        config.log(format!(
+            "Testing {} ({}), {} decimals",
+            config.token.name, config.token.symbol, config.token.decimals
+        ));
+    }
 
-    let protocol = match config.protocol {
-        Protocol::UniV2 => UniswapV2::new(),
-        _ => return Err(HPError::err_msg("Unsupported protocol".to_string())),
+    // Privileged functions are reported even if the simulation passes
+    let risky_functions =
+        find_risky_functions(config.token.address, config.block_number, &config.client).await?;
+    if config.output == OutputFormat::Text && !risky_functions.is_empty() {
+        println!("\n Privileged functions of the token:");
+        for function in &risky_functions {
+            println!(
+                "   {} ({}): {}",
+                function.signature, function.selector, function.capability
+            );
+        }
+    }
+
+    let venues = get_venues(&config).await?;
+
+    let senders = if config.multi_sender {
+        get_senders(&config, &mut Fork::new(&config)?)?
+    } else {
+        vec![(SenderKind::Configured, config.sender)]
     };
+    let mut reports = Vec::with_capacity(venues.len() * senders.len());
+
+    for venue in &venues {
+        if config.logs {
+            venue.print_pool(&config);
+        }
 
-    do_test_swap(protocol, &config, &mut cache_db).await?;
+        for &(kind, sender) in &senders {
+            let config = CliConfig {
+                sender,
+                ..config.clone()
+            };
+            // Every test runs on a fresh fork, so one test does not affect another
+            let mut fork = Fork::new(&config)?;
+            prepare_sender(kind, sender, &mut fork);
 
-    println!("\n Successful Swap \n");
+            let mut report = do_test_swap(venue, &config, &risky_functions, &mut fork).await?;
+            if config.multi_sender {
+                report.sender_kind = Some(kind);
+            }
+            if config.output == OutputFormat::Text {
+                report.print();
+            }
+            reports.push(report);
+        }
+    }
 
-    Ok(())
+    let sender_dependent = is_sender_dependent(&reports);
+    if sender_dependent && config.output == OutputFormat::Text {
+        println!(
+            "\n The verdict depends on the sender, the token treats some accounts differently"
+        );
+    }
+
+    // The owner and the contract sender only tell whether the token is sender dependent
+    let verdict = reports
+        .iter()
+        .filter(|report| report.sender_kind.is_none_or(|kind| kind.decides_verdict()))
+        .fold(Verdict::Safe, |verdict, report| {
+            verdict.worst(report.verdict)
+        });
+
+    let summary = Summary {
+        token: &config.token,
+        from_token: &config.from_token,
+        sender: config.sender,
+        block_number: config.block_number,
+        verdict,
+        reports: &reports,
+        sender_dependent,
+        risky_functions: &risky_functions,
+    };
+
+    match config.output {
+        OutputFormat::Text => {}
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string(&summary).map_err(HPError::error)?
+            )
+        }
+        OutputFormat::Pretty => println!(
+            "{}",
+            serde_json::to_string_pretty(&summary).map_err(HPError::error)?
+        ),
+    }
+
+    Ok(verdict)
 }
 
 async fn do_test_swap(
-    protocol: impl TestSwap,
+    protocol: &impl TestSwap,
     config: &CliConfig,
-    db: &mut AlloyCacheDB,
-) -> Result<()> {
-    protocol.test_swap(config, db).await
-}
-
-fn get_cache_db(client: RootProvider<Http<Client>>) -> Result<AlloyCacheDB> {
-    let db = AlloyDB::new(client, BlockId::latest()).expect("Failed to create Revm Alloy DB");
-    Ok(CacheDB::new(db))
+    risky_functions: &[RiskyFunction],
+    fork: &mut Fork,
+) -> Result<HoneypotReport> {
+    protocol.test_swap(config, risky_functions, fork).await
 }
@@ -0,0 +1,307 @@
+use alloy::{
+    dyn_abi::DynSolValue,
+    primitives::{Address, U256},
+};
+use revm::{
+    inspector_handle_register,
+    primitives::{ExecutionResult, TxKind, KECCAK_EMPTY},
+    Database, Evm,
+};
+use serde::Serialize;
+
+use crate::{
+    bytecode::{Capability, RiskyFunction},
+    cli::CliConfig,
+    error::{ErrorKind, HPError, Result},
+    fork::Fork,
+    report::HONEYPOT_TAX,
+    test_swap::{try_sell, TestSwap},
+    trace::CallTracer,
+};
+
+/// Privileged call made as the owner, followed by a sell of the bought tokens
+#[derive(Debug, Clone, Serialize)]
+pub struct OwnerAction {
+    pub signature: &'static str,
+    pub capability: Capability,
+    /// Arguments the function was called with
+    pub arguments: String,
+    /// `false` if the owner call reverted, e.g. because the contract bounds the fees
+    pub executed: bool,
+    /// Sell tax after the call, `None` if the sell failed
+    pub sell_tax: Option<f64>,
+    /// Why the owner call or the sell failed
+    pub error: Option<String>,
+    /// The call made selling fail or pointless
+    pub traps_holders: bool,
+}
+
+/// What the owner could do to the holders with the token's privileged functions
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OwnerActions {
+    /// `None` if no owner was found, then no action can be simulated
+    pub owner: Option<Address>,
+    pub actions: Vec<OwnerAction>,
+}
+
+impl OwnerActions {
+    /// Whether one of the owner's switches turns the token into a honeypot
+    pub fn is_latent_honeypot(&self) -> bool {
+        self.actions.iter().any(|action| action.traps_holders)
+    }
+}
+
+/// Returns the owner from `owner()` or `getOwner()`, or from the storage of `Ownable`,
+/// whose `_owner` is the first variable of the contract.
+pub fn find_owner(config: &CliConfig, fork: &mut Fork) -> Result<Option<Address>> {
+    if let Some(owner) = config.token.owner(config.sender, fork)? {
+        return Ok(Some(owner));
+    }
+
+    let slot = fork
+        .db
+        .storage(config.token.address, U256::ZERO)
+        .map_err(HPError::rpc_error)?;
+    // The slot may pack other variables, then it does not hold an address
+    if slot.is_zero() || slot.bit_len() > 160 {
+        return Ok(None);
+    }
+
+    // Small numbers like a supply, a counter or a flag fit too,
+    // only an account that sent a transaction or holds code is taken for the owner
+    let owner = Address::from_word(slot.into());
+    let used = fork
+        .db
+        .basic(owner)
+        .map_err(HPError::rpc_error)?
+        .is_some_and(|info| info.nonce > 0 || info.code_hash != KECCAK_EMPTY);
+
+    Ok(used.then_some(owner))
+}
+
+/// Calls every privileged function that could trap holders as the owner, with extreme values,
+/// and sells `amount` of the token after each call, on its own copy of `fork`.
+/// `fork` is the state after the buy.
+pub fn simulate_owner_actions(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    risky_functions: &[RiskyFunction],
+    amount: U256,
+    fork: &mut Fork,
+) -> Result<OwnerActions> {
+    let Some(owner) = find_owner(config, fork)? else {
+        return Ok(OwnerActions::default());
+    };
+
+    let mut actions = Vec::new();
+    for function in risky_functions {
+        for arguments in extreme_arguments(function, config.sender) {
+            let mut fork = fork.clone();
+            actions.push(simulate_action(
+                venue, config, function, owner, arguments, amount, &mut fork,
+            )?);
+        }
+    }
+
+    Ok(OwnerActions {
+        owner: Some(owner),
+        actions,
+    })
+}
+
+fn simulate_action(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    function: &RiskyFunction,
+    owner: Address,
+    arguments: Vec<DynSolValue>,
+    amount: U256,
+    fork: &mut Fork,
+) -> Result<OwnerAction> {
+    let mut action = OwnerAction {
+        signature: function.signature,
+        capability: function.capability,
+        arguments: describe_arguments(&arguments),
+        executed: false,
+        sell_tax: None,
+        error: None,
+        traps_holders: false,
+    };
+
+    let mut calldata = function.selector.to_vec();
+    calldata.extend(DynSolValue::Tuple(arguments).abi_encode_params());
+
+    match call_as(owner, config.token.address, calldata, fork) {
+        Ok(()) => action.executed = true,
+        Err(e) if e.kind() == ErrorKind::Execution => {
+            action.error = Some(e.to_string());
+            return Ok(action);
+        }
+        Err(e) => return Err(e),
+    }
+
+    let (tax, error) = try_sell(venue, config, amount, fork)?;
+    action.traps_holders = traps_holders(tax);
+    action.sell_tax = tax;
+    action.error = error;
+
+    Ok(action)
+}
+
+/// Whether holders are trapped after the owner's call, given the total sell tax of `try_sell`.
+/// A fee taken on the way into the pool, like `setFee(99, 99)` sets, is part of that tax.
+fn traps_holders(sell_tax: Option<f64>) -> bool {
+    sell_tax.is_none_or(|tax| tax > HONEYPOT_TAX)
+}
+
+/// Arguments of every call listed for the function in `RISKY_FUNCTIONS`,
+/// with `holder` as every address. Functions that cannot trap holders have none.
+fn extreme_arguments(function: &RiskyFunction, holder: Address) -> Vec<Vec<DynSolValue>> {
+    let params = parameters(function.signature);
+
+    function
+        .owner_calls
+        .iter()
+        .map(|call| {
+            params
+                .iter()
+                .map(|&param| match param {
+                    "address" => DynSolValue::Address(holder),
+                    "address[]" => DynSolValue::Array(vec![DynSolValue::Address(holder)]),
+                    "bool" => DynSolValue::Bool(call.flag),
+                    _ => DynSolValue::Uint(U256::from(call.uint), 256),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Parameter types of a function signature, e.g. `["address", "bool"]`
+fn parameters(signature: &str) -> Vec<&str> {
+    signature
+        .split_once('(')
+        .and_then(|(_, params)| params.strip_suffix(')'))
+        .map(|params| {
+            params
+                .split(',')
+                .filter(|param| !param.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn describe_arguments(arguments: &[DynSolValue]) -> String {
+    arguments
+        .iter()
+        .map(|argument| match argument {
+            DynSolValue::Address(address) => address.to_string(),
+            DynSolValue::Array(values) => format!("[{}]", describe_arguments(values)),
+            DynSolValue::Bool(flag) => flag.to_string(),
+            DynSolValue::Uint(uint, _) => uint.to_string(),
+            other => format!("{other:?}"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Calls `target` as `caller` and commits the changes.
+fn call_as(caller: Address, target: Address, calldata: Vec<u8>, fork: &mut Fork) -> Result<()> {
+    let mut evm = Evm::builder()
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
+        .with_external_context(CallTracer::default())
+        .modify_tx_env(|tx| {
+            tx.caller = caller;
+            tx.transact_to = TxKind::Call(target);
+            tx.data = calldata.into();
+        })
+        .append_handler_register(inspector_handle_register)
+        .build();
+
+    let tx = evm.transact_commit().map_err(HPError::evm_error)?;
+
+    match tx {
+        ExecutionResult::Success { .. } => Ok(()),
+        result => Err(
+            HPError::execution_failed("Owner call execution failed", &result)
+                .with_trace(&evm.context.external),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::known_functions;
+
+    const HOLDER: Address = Address::repeat_byte(0x11);
+
+    fn arguments(signature: &str) -> Vec<String> {
+        let function = known_functions()
+            .find(|function| function.signature == signature)
+            .unwrap();
+
+        extreme_arguments(&function, HOLDER)
+            .iter()
+            .map(|arguments| describe_arguments(arguments))
+            .collect()
+    }
+
+    #[test]
+    fn parses_parameters() {
+        assert_eq!(
+            parameters("setBots(address[],bool)"),
+            vec!["address[]", "bool"]
+        );
+        assert!(parameters("pause()").is_empty());
+    }
+
+    #[test]
+    fn extreme_fees() {
+        assert_eq!(
+            arguments("updateSellFees(uint256,uint256,uint256)"),
+            vec!["99, 99, 99", "9900, 9900, 9900"]
+        );
+    }
+
+    #[test]
+    fn blacklists_the_holder() {
+        assert_eq!(
+            arguments("setBots(address[],bool)"),
+            vec![format!("[{HOLDER}], true")]
+        );
+        assert_eq!(
+            arguments("blacklistAddress(address,bool)"),
+            vec![format!("{HOLDER}, true")]
+        );
+    }
+
+    #[test]
+    fn switches() {
+        assert_eq!(arguments("setTradingEnabled(bool)"), vec!["false"]);
+        assert_eq!(arguments("setCooldownEnabled(bool)"), vec!["true"]);
+        assert_eq!(arguments("setMaxTxAmount(uint256)"), vec!["0"]);
+        // Called once without arguments
+        assert_eq!(arguments("pause()"), vec![""]);
+    }
+
+    #[test]
+    fn harmless_functions_are_not_called() {
+        for signature in ["unpause()", "mint(address,uint256)", "setSwapEnabled(bool)"] {
+            assert!(arguments(signature).is_empty(), "{signature}");
+        }
+    }
+
+    #[test]
+    fn every_parameter_type_is_handled() {
+        for function in known_functions() {
+            for param in parameters(function.signature) {
+                assert!(
+                    matches!(param, "address" | "address[]" | "bool" | "uint256"),
+                    "{param} in {}",
+                    function.signature
+                );
+            }
+        }
+    }
+}
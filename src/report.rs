@@ -0,0 +1,610 @@
+use alloy::primitives::{Address, U256};
+use serde::Serialize;
+
+use crate::{
+    bytecode::RiskyFunction,
+    cli::TimeStep,
+    erc20::ERC20,
+    error::{ErrorKind, HPError, Result},
+    limits::{Limits, TradeLimit},
+    privilege::OwnerActions,
+    sender::SenderKind,
+    trace::CallFrame,
+};
+
+/// A tax above this percentage is reported as `HighTax`
+const HIGH_TAX: f64 = 10.0;
+/// A tax above this percentage makes selling pointless, so it is reported as `Honeypot`
+pub const HONEYPOT_TAX: f64 = 90.0;
+/// A sell or transfer costing this many times the gas of the buy is flagged as abnormal
+const ABNORMAL_GAS_RATIO: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Verdict {
+    /// The token can be bought, transferred and sold with low taxes
+    Safe,
+    /// The token can be bought, but not transferred or sold
+    Honeypot,
+    /// The round trip succeeded, but one of the taxes is above 10%
+    HighTax,
+    /// The test could not tell, e.g. the buy already failed
+    Inconclusive,
+}
+
+impl Verdict {
+    /// Process exit code of the verdict, errors use the codes of `ErrorKind::exit_code`
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Verdict::Safe => 0,
+            Verdict::Honeypot => 1,
+            Verdict::HighTax => 2,
+            Verdict::Inconclusive => 6,
+        }
+    }
+
+    /// Returns the verdict that is more of a concern
+    pub fn worst(self, other: Self) -> Self {
+        let severity = |verdict: Self| match verdict {
+            Verdict::Safe => 0,
+            Verdict::Inconclusive => 1,
+            Verdict::HighTax => 2,
+            Verdict::Honeypot => 3,
+        };
+
+        if severity(other) > severity(self) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Step {
+    Buy,
+    Transfer,
+    /// `transferFrom` by an approved spender
+    TransferFrom,
+    Sell,
+    /// Sell through the V2 router, which pulls the tokens with `transferFrom`
+    RouterSell,
+    /// Sell from the wallet the bought tokens were transferred to
+    RecipientSell,
+}
+
+/// Taxes taken by the token on every step of the test, in percent of the nominal amount,
+/// fees on the way into the pool included. `None` if the step was not reached.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Taxes {
+    pub buy: Option<f64>,
+    pub sell: Option<f64>,
+    pub transfer: Option<f64>,
+    pub transfer_from: Option<f64>,
+    pub router_sell: Option<f64>,
+    pub recipient_sell: Option<f64>,
+    /// Part of the buy tax taken from the quote token on its way into the pool
+    pub buy_transfer_fee: Option<f64>,
+    /// Part of the sell tax taken from the token on its way into the pool,
+    /// before the pool computes what comes out
+    pub sell_transfer_fee: Option<f64>,
+}
+
+/// Gas used by every step of the test
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct GasUsage {
+    pub buy: Option<u64>,
+    pub sell: Option<u64>,
+    pub transfer: Option<u64>,
+    /// The sell or the transfer costs more than 5 times the buy,
+    /// tokens burning gas on sells make selling uneconomic
+    pub abnormal: bool,
+}
+
+impl GasUsage {
+    fn is_abnormal(&self) -> bool {
+        let Some(buy) = self.buy else {
+            return false;
+        };
+
+        [self.sell, self.transfer]
+            .into_iter()
+            .flatten()
+            .any(|gas| gas > buy.saturating_mul(ABNORMAL_GAS_RATIO))
+    }
+}
+
+/// Balances of the pool before the test
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Reserves {
+    pub token: U256,
+    pub from_token: U256,
+}
+
+/// Amounts going in and out of the swaps. The amounts out are the ones actually received.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Amounts {
+    pub buy_in: Option<U256>,
+    pub buy_out: Option<U256>,
+    pub sell_in: Option<U256>,
+    pub sell_out: Option<U256>,
+}
+
+/// Sell retried after moving the chain forward
+#[derive(Debug, Clone, Serialize)]
+pub struct DelayedSell {
+    pub after: TimeStep,
+    pub blocks: u64,
+    pub seconds: u64,
+    /// Sell tax in percent, `None` if the sell failed
+    pub tax: Option<f64>,
+    /// Why the sell failed
+    pub error: Option<String>,
+}
+
+/// Sells retried at every time step, to find tokens that lock or unlock selling over time
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimeTravel {
+    pub sells: Vec<DelayedSell>,
+    /// First step at which selling works
+    pub earliest: Option<TimeStep>,
+    /// Last step at which selling works
+    pub latest: Option<TimeStep>,
+}
+
+impl TimeTravel {
+    pub fn new(sells: Vec<DelayedSell>) -> Self {
+        let mut working = sells.iter().filter(|sell| sell.error.is_none());
+        let earliest = working.next().map(|sell| sell.after);
+        let latest = working.next_back().map(|sell| sell.after).or(earliest);
+
+        Self {
+            sells,
+            earliest,
+            latest,
+        }
+    }
+
+    /// Whether selling stops working at one of the steps
+    pub fn is_locked(&self) -> bool {
+        self.sells.iter().any(|sell| sell.error.is_some())
+    }
+}
+
+/// Sell of a share of the bought tokens
+#[derive(Debug, Clone, Serialize)]
+pub struct PartialSell {
+    /// Share of the bought tokens sold, in percent
+    pub percent: u8,
+    pub amount: U256,
+    /// Sell tax in percent, `None` if the sell failed
+    pub tax: Option<f64>,
+    /// Why the sell failed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HoneypotReport {
+    pub verdict: Verdict,
+    /// Name of the venue the token was tested on
+    pub venue: String,
+    pub pool: Address,
+    /// Symbols of the tokens swapped through, when there is no direct pool
+    pub route: Option<Vec<String>>,
+    /// Account the round trip was made by
+    pub sender: Address,
+    /// Which of the senders it is, in multi-sender mode
+    pub sender_kind: Option<SenderKind>,
+    pub block_number: u64,
+    /// `None` for routes, whose pairs each hold only one of the tokens
+    pub reserves: Option<Reserves>,
+    pub amounts: Amounts,
+    pub taxes: Taxes,
+    pub gas: GasUsage,
+    pub failing_step: Option<Step>,
+    /// Error of the failing step
+    pub error: Option<String>,
+    pub revert_reason: Option<String>,
+    pub halt_reason: Option<String>,
+    /// Call the failure originated from
+    pub reverted_in: Option<CallFrame>,
+    /// Every call made by the failing transaction
+    pub call_trace: Vec<CallFrame>,
+    /// Sells of growing shares of the bought tokens, if enabled
+    pub ladder: Option<Vec<PartialSell>>,
+    /// Sells retried later in time, if enabled
+    pub time_travel: Option<TimeTravel>,
+    /// Largest buy and sell, if enabled
+    pub limits: Option<Limits>,
+    /// Privileged functions called as the owner before selling, if enabled
+    pub owner_actions: Option<OwnerActions>,
+    /// The owner can turn the token into a honeypot with one of its functions
+    pub latent_honeypot: bool,
+}
+
+impl HoneypotReport {
+    pub fn new(venue: String, pool: Address, sender: Address, block_number: u64) -> Self {
+        Self {
+            verdict: Verdict::Inconclusive,
+            venue,
+            pool,
+            route: None,
+            sender,
+            sender_kind: None,
+            block_number,
+            reserves: None,
+            amounts: Amounts::default(),
+            taxes: Taxes::default(),
+            gas: GasUsage::default(),
+            failing_step: None,
+            error: None,
+            revert_reason: None,
+            halt_reason: None,
+            reverted_in: None,
+            call_trace: Vec::new(),
+            ladder: None,
+            time_travel: None,
+            limits: None,
+            owner_actions: None,
+            latent_honeypot: false,
+        }
+    }
+
+    /// Records the failed step if the error comes from the simulated transaction.
+    /// Any other error (RPC, decoding...) means the test itself failed, so it is returned.
+    pub fn fail(mut self, step: Step, error: HPError) -> Result<Self> {
+        if error.kind() != ErrorKind::Execution {
+            return Err(error);
+        }
+
+        self.verdict = match step {
+            // Nothing was bought, so there is nothing to tell about selling
+            Step::Buy => Verdict::Inconclusive,
+            Step::Transfer
+            | Step::TransferFrom
+            | Step::Sell
+            | Step::RouterSell
+            | Step::RecipientSell => Verdict::Honeypot,
+        };
+        self.failing_step = Some(step);
+        if let Some(failure) = error.failure() {
+            self.revert_reason = failure.revert_reason.clone();
+            self.halt_reason = failure.halt_reason.clone();
+            self.reverted_in = failure.origin.clone();
+            self.call_trace = failure.call_trace.clone();
+        }
+        self.error = Some(error.to_string());
+
+        Ok(self)
+    }
+
+    /// Sets the verdict of a completed round trip based on the measured taxes.
+    pub fn finish(mut self) -> Self {
+        self.gas.abnormal = self.gas.is_abnormal();
+        self.latent_honeypot = self
+            .owner_actions
+            .as_ref()
+            .is_some_and(|owner_actions| owner_actions.is_latent_honeypot());
+
+        let max_tax = [
+            self.taxes.buy,
+            self.taxes.sell,
+            self.taxes.transfer,
+            self.taxes.transfer_from,
+            self.taxes.router_sell,
+            self.taxes.recipient_sell,
+        ]
+        .into_iter()
+        .flatten()
+        .fold(0.0, f64::max);
+
+        let locked = self
+            .time_travel
+            .as_ref()
+            .is_some_and(|time_travel| time_travel.is_locked());
+        let capped = self
+            .ladder
+            .iter()
+            .flatten()
+            .any(|sell| sell.error.is_some());
+
+        // A sell that works now but not later, or only for some amounts, still traps the holders
+        self.verdict = if max_tax > HONEYPOT_TAX || locked || capped {
+            Verdict::Honeypot
+        } else if max_tax > HIGH_TAX {
+            Verdict::HighTax
+        } else {
+            Verdict::Safe
+        };
+
+        self
+    }
+
+    pub fn print(&self) {
+        println!("\n {} ({}): {:?}", self.venue, self.pool, self.verdict);
+        if let Some(kind) = self.sender_kind {
+            println!("   Sender: {} ({:?})", self.sender, kind);
+        }
+        println!("   Block: {}", self.block_number);
+        if let Some(route) = &self.route {
+            println!("   Route: {}", route.join(" -> "));
+        }
+
+        if let Some(reserves) = &self.reserves {
+            println!(
+                "   Reserves: {} token, {} from token",
+                reserves.token, reserves.from_token
+            );
+        }
+
+        for (name, tax) in [
+            ("Buy", self.taxes.buy),
+            ("Transfer", self.taxes.transfer),
+            ("TransferFrom", self.taxes.transfer_from),
+            ("Sell", self.taxes.sell),
+            ("Router sell", self.taxes.router_sell),
+            ("Recipient sell", self.taxes.recipient_sell),
+        ] {
+            if let Some(tax) = tax {
+                println!("   {} tax: {}%", name, tax);
+            }
+        }
+        for (name, fee) in [
+            ("Buy", self.taxes.buy_transfer_fee),
+            ("Sell", self.taxes.sell_transfer_fee),
+        ] {
+            if let Some(fee) = fee.filter(|fee| *fee > 0.0) {
+                println!("   {} transfer fee (part of the tax): {}%", name, fee);
+            }
+        }
+
+        for (name, gas) in [
+            ("Buy", self.gas.buy),
+            ("Transfer", self.gas.transfer),
+            ("Sell", self.gas.sell),
+        ] {
+            if let Some(gas) = gas {
+                println!("   {} gas: {}", name, gas);
+            }
+        }
+        if self.gas.abnormal {
+            println!(
+                "   Abnormal gas: selling or transferring costs more than {} times the buy",
+                ABNORMAL_GAS_RATIO
+            );
+        }
+
+        if let Some(ladder) = &self.ladder {
+            println!("   Partial sells:");
+            for sell in ladder {
+                println!(
+                    "     {}% ({}): {}",
+                    sell.percent,
+                    sell.amount,
+                    sell_outcome(sell.tax, &sell.error)
+                );
+            }
+        }
+
+        if let Some(time_travel) = &self.time_travel {
+            println!("   Sells after time travel:");
+            for sell in &time_travel.sells {
+                println!(
+                    "     {:?} (+{} blocks, +{}s): {}",
+                    sell.after,
+                    sell.blocks,
+                    sell.seconds,
+                    sell_outcome(sell.tax, &sell.error)
+                );
+            }
+            match (time_travel.earliest, time_travel.latest) {
+                (Some(earliest), Some(latest)) => {
+                    println!("   Selling works from {:?} to {:?}", earliest, latest)
+                }
+                _ => println!("   Selling never works"),
+            }
+        }
+
+        if let Some(limits) = &self.limits {
+            println!("   Total supply: {}", limits.total_supply);
+            for (name, limit) in [("buy", &limits.max_buy), ("sell", &limits.max_sell)] {
+                println!("   Max {}: {}", name, describe_limit(limit));
+            }
+            if let Some(error) = &limits.error {
+                println!("   {}", error);
+            }
+        }
+
+        if let Some(owner_actions) = &self.owner_actions {
+            match owner_actions.owner {
+                Some(owner) => println!("   Owner actions ({}):", owner),
+                None => println!("   Owner actions: no owner found"),
+            }
+            for action in &owner_actions.actions {
+                let outcome = if !action.executed {
+                    "owner call reverted".to_string()
+                } else if action.traps_holders {
+                    format!(
+                        "traps holders, sell {}",
+                        sell_outcome(action.sell_tax, &action.error)
+                    )
+                } else {
+                    format!("sell {}", sell_outcome(action.sell_tax, &action.error))
+                };
+                println!(
+                    "     {}({}): {}",
+                    action.signature.split('(').next().unwrap_or_default(),
+                    action.arguments,
+                    outcome
+                );
+            }
+            if self.latent_honeypot {
+                println!("   Latent honeypot: the owner can stop holders from selling");
+            }
+        }
+
+        if let Some(step) = self.failing_step {
+            println!("   Failed at: {:?}", step);
+        }
+        if let Some(error) = &self.error {
+            println!("   Error: {}", error);
+        }
+        if let Some(reason) = &self.revert_reason {
+            println!("   Revert reason: {}", reason);
+        }
+        if let Some(reason) = &self.halt_reason {
+            println!("   Halt reason: {}", reason);
+        }
+        if let Some(frame) = &self.reverted_in {
+            let pool = if frame.target == self.pool {
+                " (pool)"
+            } else {
+                ""
+            };
+            println!(
+                "   Reverted in: {}{} {}",
+                frame.describe_target(),
+                pool,
+                frame.describe()
+            );
+        }
+        if !self.call_trace.is_empty() {
+            println!("   Call trace:");
+            for frame in &self.call_trace {
+                println!(
+                    "     {}{} -> {} {}{}",
+                    "  ".repeat(frame.depth),
+                    frame.caller,
+                    frame.describe_target(),
+                    frame.describe(),
+                    if frame.success { "" } else { " [failed]" }
+                );
+            }
+        }
+    }
+}
+
+/// Describes the outcome of a sell retried on a copy of the fork
+fn sell_outcome(tax: Option<f64>, error: &Option<String>) -> String {
+    match (tax, error) {
+        (Some(tax), _) => format!("ok, {}% tax", tax),
+        (None, Some(error)) => format!("failed, {}", error),
+        (None, None) => "failed".to_string(),
+    }
+}
+
+fn describe_limit(limit: &Option<TradeLimit>) -> String {
+    match limit {
+        Some(limit) if limit.limited => {
+            format!("{} ({}% of supply)", limit.amount, limit.percent_of_supply)
+        }
+        Some(limit) => format!(
+            "no limit found up to {} ({}% of supply)",
+            limit.amount, limit.percent_of_supply
+        ),
+        None => "not even the tested amount succeeded".to_string(),
+    }
+}
+
+/// Everything the tool found out about the token, printed as one JSON object
+#[derive(Debug, Serialize)]
+pub struct Summary<'a> {
+    pub token: &'a ERC20,
+    pub from_token: &'a ERC20,
+    pub sender: Address,
+    pub block_number: u64,
+    /// Worst verdict of the reports, the one the exit code is based on.
+    /// In multi-sender mode, only the configured and the fresh senders count
+    pub verdict: Verdict,
+    pub reports: &'a [HoneypotReport],
+    /// The same pool gave different verdicts depending on the sender,
+    /// the token whitelists or blacklists some accounts
+    pub sender_dependent: bool,
+    /// Functions letting the owner blacklist, limit, tax or pause trading
+    pub risky_functions: &'a [RiskyFunction],
+}
+
+/// Returns whether any pool was given different verdicts for different senders
+pub fn is_sender_dependent(reports: &[HoneypotReport]) -> bool {
+    reports.iter().any(|report| {
+        reports
+            .iter()
+            .any(|other| other.pool == report.pool && other.verdict != report.verdict)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sells(outcomes: &[(TimeStep, bool)]) -> Vec<DelayedSell> {
+        outcomes
+            .iter()
+            .map(|&(after, works)| {
+                let (blocks, seconds) = after.offset();
+                DelayedSell {
+                    after,
+                    blocks,
+                    seconds,
+                    tax: works.then_some(0.0),
+                    error: (!works).then(|| "reverted".to_string()),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn time_travel_unlocked_later() {
+        let time_travel = TimeTravel::new(sells(&[
+            (TimeStep::Block, false),
+            (TimeStep::Minute, true),
+            (TimeStep::Hour, true),
+        ]));
+
+        assert_eq!(time_travel.earliest, Some(TimeStep::Minute));
+        assert_eq!(time_travel.latest, Some(TimeStep::Hour));
+        assert!(time_travel.is_locked());
+    }
+
+    #[test]
+    fn time_travel_locked_later() {
+        let time_travel = TimeTravel::new(sells(&[
+            (TimeStep::Block, true),
+            (TimeStep::Minute, true),
+            (TimeStep::Day, false),
+        ]));
+
+        assert_eq!(time_travel.earliest, Some(TimeStep::Block));
+        assert_eq!(time_travel.latest, Some(TimeStep::Minute));
+        assert!(time_travel.is_locked());
+    }
+
+    #[test]
+    fn time_travel_single_working_step() {
+        let time_travel = TimeTravel::new(sells(&[
+            (TimeStep::Block, false),
+            (TimeStep::Hour, true),
+            (TimeStep::Day, false),
+        ]));
+
+        assert_eq!(time_travel.earliest, Some(TimeStep::Hour));
+        assert_eq!(time_travel.latest, Some(TimeStep::Hour));
+    }
+
+    #[test]
+    fn time_travel_never_works() {
+        let time_travel =
+            TimeTravel::new(sells(&[(TimeStep::Block, false), (TimeStep::Day, false)]));
+
+        assert_eq!(time_travel.earliest, None);
+        assert_eq!(time_travel.latest, None);
+    }
+
+    #[test]
+    fn time_travel_always_works() {
+        let time_travel = TimeTravel::new(sells(&[(TimeStep::Block, true), (TimeStep::Day, true)]));
+
+        assert_eq!(time_travel.earliest, Some(TimeStep::Block));
+        assert_eq!(time_travel.latest, Some(TimeStep::Day));
+        assert!(!time_travel.is_locked());
+    }
+}
@@ -0,0 +1,173 @@
+use alloy::{
+    hex, sol,
+    sol_types::{decode_revert_reason, SolInterface},
+};
+use revm::primitives::{ExecutionResult, Output};
+use serde::Serialize;
+use std::fmt::Display;
+
+use crate::trace::{CallFrame, CallTracer};
+
+sol! {
+    /// Custom errors commonly thrown by tokens, mostly from OpenZeppelin contracts
+    #[derive(Debug)]
+    interface KnownErrors {
+        error ERC20InsufficientBalance(address sender, uint256 balance, uint256 needed);
+        error ERC20InvalidSender(address sender);
+        error ERC20InvalidReceiver(address receiver);
+        error ERC20InsufficientAllowance(address spender, uint256 allowance, uint256 needed);
+        error ERC20InvalidApprover(address approver);
+        error ERC20InvalidSpender(address spender);
+        error OwnableUnauthorizedAccount(address account);
+        error OwnableInvalidOwner(address owner);
+        error EnforcedPause();
+        error ReentrancyGuardReentrantCall();
+        error SafeERC20FailedOperation(address token);
+    }
+}
+
+/// Uniswap V3 reverts with short codes, these are the ones a swap can hit
+const UNIV3_REVERT_CODES: [(&str, &str); 8] = [
+    ("STF", "safeTransferFrom failed"),
+    ("TF", "transfer failed"),
+    ("IIA", "insufficient input amount"),
+    ("AS", "amount specified is zero"),
+    ("SPL", "sqrt price limit out of range"),
+    ("LOK", "pool is locked"),
+    ("Too little received", "output below minimum"),
+    ("Transaction too old", "deadline passed"),
+];
+
+/// Why a simulated transaction did not succeed
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecutionFailure {
+    /// Decoded revert data, if the transaction reverted
+    pub revert_reason: Option<String>,
+    /// Reason the EVM halted, e.g. `OutOfGas`
+    pub halt_reason: Option<String>,
+    /// Call the failure originated from, e.g. a hidden contract the token asks for permission
+    pub origin: Option<CallFrame>,
+    /// Every call made by the transaction
+    pub call_trace: Vec<CallFrame>,
+}
+
+impl ExecutionFailure {
+    pub fn from_result(result: &ExecutionResult) -> Self {
+        match result {
+            ExecutionResult::Revert { output, .. } => Self {
+                revert_reason: Some(decode_revert(output)),
+                halt_reason: None,
+                origin: None,
+                call_trace: Vec::new(),
+            },
+            ExecutionResult::Halt { reason, .. } => Self {
+                revert_reason: None,
+                halt_reason: Some(format!("{reason:?}")),
+                origin: None,
+                call_trace: Vec::new(),
+            },
+            // A successful call with unexpected output, e.g. a contract creation
+            ExecutionResult::Success { output, .. } => Self {
+                revert_reason: match output {
+                    Output::Call(_) => None,
+                    Output::Create(..) => Some("unexpected contract creation".to_string()),
+                },
+                halt_reason: None,
+                origin: None,
+                call_trace: Vec::new(),
+            },
+        }
+    }
+
+    pub fn with_trace(mut self, tracer: &CallTracer) -> Self {
+        self.origin = tracer.failure_origin().cloned();
+        self.call_trace = tracer.frames().to_vec();
+        self
+    }
+}
+
+impl Display for ExecutionFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.revert_reason, &self.halt_reason) {
+            (Some(reason), _) => write!(f, "reverted: {}", reason),
+            (None, Some(reason)) => write!(f, "halted: {}", reason),
+            (None, None) => write!(f, "unexpected output"),
+        }
+    }
+}
+
+/// Decodes `Error(string)`, `Panic(uint256)` and well known custom errors into a readable reason.
+pub fn decode_revert(output: &[u8]) -> String {
+    if output.is_empty() {
+        return "no reason given".to_string();
+    }
+
+    if let Ok(error) = KnownErrors::KnownErrorsErrors::abi_decode(output, true) {
+        return format!("{error:?}");
+    }
+
+    if let Some(reason) = decode_revert_reason(output) {
+        // `decode_revert_reason` prefixes the message with "revert: "
+        let message = reason.strip_prefix("revert: ").unwrap_or(&reason);
+
+        return match UNIV3_REVERT_CODES.iter().find(|(code, _)| *code == message) {
+            Some((code, description)) => format!("{code} ({description})"),
+            None => message.to_string(),
+        };
+    }
+
+    // Unknown custom error, the selector can be looked up in a signature database
+    match output.get(..4) {
+        Some(selector) => format!("custom error 0x{}", hex::encode(selector)),
+        None => format!("0x{}", hex::encode(output)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::U256,
+        sol_types::{Panic, Revert, SolError},
+    };
+
+    #[test]
+    fn decodes_error_string() {
+        let output = Revert::from("Trading not open").abi_encode();
+
+        assert_eq!(decode_revert(&output), "Trading not open");
+    }
+
+    #[test]
+    fn describes_univ3_codes() {
+        let output = Revert::from("STF").abi_encode();
+
+        assert_eq!(decode_revert(&output), "STF (safeTransferFrom failed)");
+    }
+
+    #[test]
+    fn decodes_panic() {
+        let output = Panic::from(U256::from(0x11)).abi_encode();
+
+        assert!(decode_revert(&output).contains("overflow"));
+    }
+
+    #[test]
+    fn decodes_known_custom_error() {
+        let output = KnownErrors::EnforcedPause {}.abi_encode();
+
+        assert!(decode_revert(&output).contains("EnforcedPause"));
+    }
+
+    #[test]
+    fn shows_unknown_selector() {
+        let output = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01];
+
+        assert_eq!(decode_revert(&output), "custom error 0xdeadbeef");
+    }
+
+    #[test]
+    fn handles_empty_output() {
+        assert_eq!(decode_revert(&[]), "no reason given");
+    }
+}
@@ -0,0 +1,205 @@
+use alloy::primitives::{address, Address, U256};
+
+use crate::{
+    cli::CliConfig,
+    erc20::ERC20,
+    error::Result,
+    fork::Fork,
+    report::Reserves,
+    test_swap::{SwapResult, TestSwap},
+    uniswapv2::{find_pair, router_balance_of, univ2_router_swap, univ2_swap, UniV2Pair, V2Fork},
+    AlloyProvider,
+};
+
+/// Tokens paired with most quote tokens, used to reach tokens without a direct pair
+const INTERMEDIATES: [(Address, &str, &str, u8); 3] = [
+    (
+        address!("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"),
+        "USD Coin",
+        "USDC",
+        6,
+    ),
+    (
+        address!("dAC17F958D2ee523a2206206994597C13D831ec7"),
+        "Tether USD",
+        "USDT",
+        6,
+    ),
+    (
+        address!("6B175474e89094C44Da98b954EedeAC495271d0F"),
+        "Dai Stablecoin",
+        "DAI",
+        18,
+    ),
+];
+
+/// Two hops route through an intermediate token on a Uniswap V2 fork,
+/// e.g. WETH -> USDC -> token and back.
+pub struct UniswapV2Route {
+    pub fork: V2Fork,
+    pub quote_token: ERC20,
+    pub via: ERC20,
+    pub token: ERC20,
+    /// Pair of the quote token and the intermediate token
+    pub quote_pair: UniV2Pair,
+    /// Pair of the intermediate token and the tested token
+    pub token_pair: UniV2Pair,
+    /// Swap through the router instead of the pairs, see `univ2_router_swap`
+    pub via_router: bool,
+}
+
+impl TestSwap for UniswapV2Route {
+    fn name(&self) -> String {
+        format!("{} via {}", self.fork.name, self.via.symbol)
+    }
+
+    /// The pair of the tested token, where its taxes are taken
+    fn pool(&self) -> Address {
+        self.token_pair.address
+    }
+
+    fn balance_of(&self, token: &ERC20, owner: Address, fork: &mut Fork) -> Result<U256> {
+        router_balance_of(self.via_router, token, owner, fork)
+    }
+
+    fn router_path(&self, token_in: &ERC20, token_out: &ERC20) -> Option<(Address, Vec<Address>)> {
+        Some((
+            self.fork.router,
+            vec![token_in.address, self.via.address, token_out.address],
+        ))
+    }
+
+    /// Each pair of the route only holds one of the tested tokens
+    fn reserves(&self, _config: &CliConfig, _fork: &mut Fork) -> Result<Option<Reserves>> {
+        Ok(None)
+    }
+
+    fn route(&self) -> Option<Vec<String>> {
+        Some(vec![
+            self.quote_token.symbol.clone(),
+            self.via.symbol.clone(),
+            self.token.symbol.clone(),
+        ])
+    }
+
+    fn swap(
+        &self,
+        sender: Address,
+        token_in: &ERC20,
+        token_out: &ERC20,
+        amount_in: U256,
+        fork: &mut Fork,
+    ) -> Result<SwapResult> {
+        if self.via_router {
+            let path = vec![token_in.address, self.via.address, token_out.address];
+            return univ2_router_swap(sender, self.fork.router, token_in, path, amount_in, fork);
+        }
+
+        let hops = if token_in.address == self.quote_token.address {
+            [
+                (&self.quote_pair, token_in, &self.via),
+                (&self.token_pair, &self.via, token_out),
+            ]
+        } else {
+            [
+                (&self.token_pair, token_in, &self.via),
+                (&self.quote_pair, &self.via, token_out),
+            ]
+        };
+
+        let mut amount = amount_in;
+        let mut result = SwapResult {
+            amount_out: U256::ZERO,
+            pool_amount_in: U256::ZERO,
+            gas_used: 0,
+        };
+
+        for (index, (pair, hop_in, hop_out)) in hops.into_iter().enumerate() {
+            let (swap, received) =
+                swap_hop(sender, pair, hop_in, hop_out, amount, fork).map_err(|e| {
+                    e.context(format!(
+                        "Hop {} of {} ({} -> {} on pair {})",
+                        index + 1,
+                        hops.len(),
+                        hop_in.symbol,
+                        hop_out.symbol,
+                        pair.address
+                    ))
+                })?;
+
+            // Only the first hop swaps the tokens of the sender
+            if index == 0 {
+                result.pool_amount_in = swap.pool_amount_in;
+            }
+            // The next hop swaps what was actually received
+            amount = received;
+            result.amount_out = swap.amount_out;
+            result.gas_used += swap.gas_used;
+        }
+
+        Ok(result)
+    }
+}
+
+/// Swaps on one pair of the route and returns the amount the sender received.
+fn swap_hop(
+    sender: Address,
+    pair: &UniV2Pair,
+    token_in: &ERC20,
+    token_out: &ERC20,
+    amount_in: U256,
+    fork: &mut Fork,
+) -> Result<(SwapResult, U256)> {
+    let balance_before = token_out.balance_of(sender, sender, fork)?;
+    let swap = univ2_swap(sender, pair, token_in.clone(), amount_in, fork)?;
+    let balance_after = token_out.balance_of(sender, sender, fork)?;
+
+    Ok((swap, balance_after.saturating_sub(balance_before)))
+}
+
+/// Returns the routes from the quote token to the token through every intermediate token
+/// both are paired with on the fork at the block.
+pub async fn find_routes(
+    fork: &V2Fork,
+    quote_token: &ERC20,
+    token: &ERC20,
+    via_router: bool,
+    block_number: u64,
+    client: &AlloyProvider,
+) -> Result<Vec<UniswapV2Route>> {
+    let mut routes = Vec::new();
+
+    for (address, name, symbol, decimals) in INTERMEDIATES {
+        if address == quote_token.address || address == token.address {
+            continue;
+        }
+
+        let Some(quote_pair) =
+            find_pair(fork, &quote_token.address, &address, block_number, client).await?
+        else {
+            continue;
+        };
+        let Some(token_pair) =
+            find_pair(fork, &address, &token.address, block_number, client).await?
+        else {
+            continue;
+        };
+
+        routes.push(UniswapV2Route {
+            fork: *fork,
+            quote_token: quote_token.clone(),
+            via: ERC20 {
+                address,
+                name: name.to_string(),
+                symbol: symbol.to_string(),
+                decimals,
+            },
+            token: token.clone(),
+            quote_pair,
+            token_pair,
+            via_router,
+        });
+    }
+
+    Ok(routes)
+}
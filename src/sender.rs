@@ -0,0 +1,62 @@
+use alloy::primitives::{Address, Bytes};
+use revm::primitives::{AccountInfo, Bytecode};
+use serde::Serialize;
+
+use crate::{
+    cli::CliConfig, error::Result, fork::Fork, privilege::find_owner, test_swap::random_address,
+};
+
+/// Who the round trip is made by in multi-sender mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SenderKind {
+    /// `--sender`, or the default account
+    Configured,
+    /// An empty account nobody ever used
+    FreshEoa,
+    /// The owner of the token, often whitelisted by honeypots
+    Owner,
+    /// An address with code, for tokens treating contracts differently
+    Contract,
+}
+
+impl SenderKind {
+    /// Whether the sender's verdict is the one regular holders get. The owner is often
+    /// whitelisted and contracts are often blocked by anti-bot checks, so their reports
+    /// only show that the token is sender dependent.
+    pub fn decides_verdict(&self) -> bool {
+        matches!(self, SenderKind::Configured | SenderKind::FreshEoa)
+    }
+}
+
+/// Returns the senders to test the token with. The owner is only returned if the token has one,
+/// see `find_owner`.
+pub fn get_senders(config: &CliConfig, fork: &mut Fork) -> Result<Vec<(SenderKind, Address)>> {
+    let mut senders = vec![
+        (SenderKind::Configured, config.sender),
+        (SenderKind::FreshEoa, random_address()),
+    ];
+    match find_owner(config, fork)? {
+        Some(owner) => senders.push((SenderKind::Owner, owner)),
+        None => config.log("No owner found, e.g. renounced, not testing as the owner"),
+    }
+    senders.push((SenderKind::Contract, random_address()));
+
+    // The configured sender may already be the owner
+    let mut unique = Vec::with_capacity(senders.len());
+    for (kind, address) in senders {
+        if !unique.iter().any(|(_, known)| *known == address) {
+            unique.push((kind, address));
+        }
+    }
+
+    Ok(unique)
+}
+
+/// Gives the contract sender its code, a single `STOP`, so `extcodesize` checks see a contract.
+pub fn prepare_sender(kind: SenderKind, address: Address, fork: &mut Fork) {
+    if kind == SenderKind::Contract {
+        let code = Bytecode::new_raw(Bytes::from_static(&[0x00]));
+        fork.db
+            .insert_account_info(address, AccountInfo::from_bytecode(code));
+    }
+}
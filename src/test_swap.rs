@@ -1,5 +1,542 @@
-use crate::{cli::CliConfig, error::Result, AlloyCacheDB};
+use alloy::primitives::{keccak256, Address, U256};
+use std::{
+    array,
+    hash::{BuildHasher, RandomState},
+};
+
+use crate::{
+    bytecode::RiskyFunction,
+    cli::CliConfig,
+    erc20::{eth_balance, fund_eth, ERC20, WETH},
+    error::{ErrorKind, HPError, Result},
+    fork::Fork,
+    limits::find_limits,
+    privilege::simulate_owner_actions,
+    report::{DelayedSell, HoneypotReport, PartialSell, Reserves, Step, TimeTravel},
+    uniswapv2::univ2_router_swap,
+};
+
+/// Outcome of a single swap
+#[derive(Debug, Clone, Copy)]
+pub struct SwapResult {
+    /// Amount out calculated by the pool, before any token tax is taken
+    pub amount_out: U256,
+    /// Amount the pool received, less than the amount in if the token takes a fee on transfer
+    pub pool_amount_in: U256,
+    pub gas_used: u64,
+}
+
+/// Outcome of a swap or transfer as seen by the receiver's balance
+pub struct Leg {
+    /// Amount out calculated by the pool, or the amount transferred
+    pub expected: U256,
+    pub received: U256,
+    pub gas_used: u64,
+    /// Share of the amount in taken before it reached the pool, in percent
+    pub transfer_fee: f64,
+}
+
+impl Leg {
+    /// Share of the nominal amount lost on the leg, in percent: the fee taken on the way
+    /// into the pool compounded with the tax taken from what came out of it.
+    pub fn tax(&self) -> f64 {
+        total_tax(self.transfer_fee, tax(self.expected, self.received))
+    }
+}
 
 pub trait TestSwap {
-    async fn test_swap(&self, config: &CliConfig, db: &mut AlloyCacheDB) -> Result<()>;
+    /// Name of the venue, e.g. "Uniswap V2"
+    fn name(&self) -> String;
+
+    /// Address of the pool the swaps go through
+    fn pool(&self) -> Address;
+
+    /// Balance of `owner` as the swaps see it
+    fn balance_of(&self, token: &ERC20, owner: Address, fork: &mut Fork) -> Result<U256> {
+        token.balance_of(owner, owner, fork)
+    }
+
+    /// `UniswapV2Router02` the venue can be traded through,
+    /// with the path from `token_in` to `token_out`
+    fn router_path(
+        &self,
+        _token_in: &ERC20,
+        _token_out: &ERC20,
+    ) -> Option<(Address, Vec<Address>)> {
+        None
+    }
+
+    /// Balances of both tokens in the pool, `None` if no single pool holds both
+    fn reserves(&self, config: &CliConfig, fork: &mut Fork) -> Result<Option<Reserves>> {
+        Ok(Some(Reserves {
+            token: config.token.balance_of(self.pool(), config.sender, fork)?,
+            from_token: config
+                .from_token
+                .balance_of(self.pool(), config.sender, fork)?,
+        }))
+    }
+
+    /// Symbols of the tokens the swaps go through, if the venue routes through other tokens
+    fn route(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Swaps `amount_in` of `token_in` for `token_out` as `sender`.
+    fn swap(
+        &self,
+        sender: Address,
+        token_in: &ERC20,
+        token_out: &ERC20,
+        amount_in: U256,
+        fork: &mut Fork,
+    ) -> Result<SwapResult>;
+
+    /// Buys the token with the quote token and sells it back, measuring the tax of every step.
+    /// `risky_functions` are the privileged functions the owner actions call.
+    async fn test_swap(
+        &self,
+        config: &CliConfig,
+        risky_functions: &[RiskyFunction],
+        fork: &mut Fork,
+    ) -> Result<HoneypotReport> {
+        let mut report =
+            HoneypotReport::new(self.name(), self.pool(), config.sender, config.block_number);
+        report.route = self.route();
+
+        // 1. Add ETH and the quote token amount to account,
+        //    with enough ETH to buy with it through the router
+        let one_eth = U256::from(10_u128.pow(18));
+        fund_eth(config.sender, one_eth.max(config.amount), fork)?;
+        config.from_token.set_balance(
+            config.quote_balance_slot,
+            config.sender,
+            config.amount,
+            fork,
+        )?;
+
+        if config.logs {
+            print_balances(config, fork, "before")?;
+        }
+
+        let amount_in = config.amount;
+
+        report.reserves = self.reserves(config, fork)?;
+
+        // The limits are searched from the state before the buy below
+        let mut before_buy = config.limits.then(|| fork.clone());
+
+        // 2. Swap the quote token for Token
+        report.amounts.buy_in = Some(amount_in);
+        let buy = match swap_and_measure(
+            self,
+            config,
+            &config.from_token,
+            &config.token,
+            amount_in,
+            fork,
+        ) {
+            Ok(leg) => leg,
+            Err(e) => return report.fail(Step::Buy, e),
+        };
+        report.amounts.buy_out = Some(buy.received);
+        report.taxes.buy = Some(buy.tax());
+        report.taxes.buy_transfer_fee = Some(buy.transfer_fee);
+        report.gas.buy = Some(buy.gas_used);
+
+        // Steps other than the buy and the sell run on copies of the fork. Their failure is
+        // recorded once the round trip is done, so the report still tells whether selling works.
+        let mut failure = None;
+
+        // 3. Transfer the tokens to a fresh wallet on a copy of the fork,
+        //    so the sell below still has the whole balance.
+        //    The fresh wallet sells them once the sender has.
+        let recipient = random_address();
+        let mut transferred = fork.clone();
+        let transfer = side_step(
+            Step::Transfer,
+            transfer_to(config, recipient, buy.received, &mut transferred),
+            &mut failure,
+        )?;
+        if let Some(transfer) = &transfer {
+            report.taxes.transfer = Some(transfer.tax());
+            report.gas.transfer = Some(transfer.gas_used);
+        }
+
+        //    Routers and other contracts move tokens with `approve` and `transferFrom` instead,
+        //    which some tokens block on their own.
+        let transfer_from = side_step(
+            Step::TransferFrom,
+            transfer_from_spender(config, buy.received, &mut fork.clone()),
+            &mut failure,
+        )?;
+        report.taxes.transfer_from = transfer_from.map(|leg| leg.tax());
+
+        // 4. Sell shares of the tokens on copies of the fork,
+        //    for tokens that block sells above a threshold or only allow tiny ones.
+        //    Then retry the sell later in time, for tokens that lock or unlock selling after a while.
+        //    Then search the largest buy and sell, for anti-whale limits.
+        if config.ladder {
+            report.ladder = Some(ladder_sells(self, config, buy.received, fork)?);
+        }
+        if !config.time_travel.is_empty() {
+            report.time_travel = Some(time_travel_sells(self, config, buy.received, fork)?);
+        }
+        if let Some(before_buy) = before_buy.as_mut() {
+            report.limits = Some(find_limits(self, config, buy.received, before_buy)?);
+        }
+
+        // The router sell and the owner actions below run on the state before this sell
+        let router_path = self.router_path(&config.token, &config.from_token);
+        let before_sell = (router_path.is_some() || config.owner_actions).then(|| fork.clone());
+
+        // 5. Swap Token for the quote token
+        //    this is what shows if the token is a honeypot or not.
+        report.amounts.sell_in = Some(buy.received);
+        let sell = match swap_and_measure(
+            self,
+            config,
+            &config.token,
+            &config.from_token,
+            buy.received,
+            fork,
+        ) {
+            Ok(leg) => leg,
+            Err(e) => return report.fail(Step::Sell, e),
+        };
+        report.amounts.sell_out = Some(sell.received);
+        report.taxes.sell = Some(sell.tax());
+        report.taxes.sell_transfer_fee = Some(sell.transfer_fee);
+        report.gas.sell = Some(sell.gas_used);
+
+        if config.logs {
+            print_balances(config, fork, "after")?;
+        }
+
+        // 6. Sell through the router as wallets do
+        if let (Some((router, path)), Some(before_sell)) = (router_path, before_sell.as_ref()) {
+            let router_sell = side_step(
+                Step::RouterSell,
+                router_sell(config, router, path, buy.received, &mut before_sell.clone()),
+                &mut failure,
+            )?;
+            report.taxes.router_sell = router_sell.map(|leg| leg.tax());
+        }
+
+        // 7. Sell from the wallet the tokens were transferred to,
+        //    for tokens blocking transfers between holders or blacklisting recipients.
+        if let Some(transfer) = &transfer {
+            let recipient_config = CliConfig {
+                sender: recipient,
+                ..config.clone()
+            };
+            let recipient_sell = side_step(
+                Step::RecipientSell,
+                swap_and_measure(
+                    self,
+                    &recipient_config,
+                    &config.token,
+                    &config.from_token,
+                    transfer.received,
+                    &mut transferred,
+                ),
+                &mut failure,
+            )?;
+            report.taxes.recipient_sell = recipient_sell.map(|leg| leg.tax());
+        }
+
+        // 8. The sell works today, check whether the owner could make it fail
+        //    with one of the token's privileged functions.
+        if let (true, Some(mut before_sell)) = (config.owner_actions, before_sell) {
+            report.owner_actions = Some(simulate_owner_actions(
+                self,
+                config,
+                risky_functions,
+                buy.received,
+                &mut before_sell,
+            )?);
+        }
+
+        let report = report.finish();
+        match failure {
+            Some((step, e)) => report.fail(step, e),
+            None => Ok(report),
+        }
+    }
+}
+
+/// Returns the leg of a step that does not stop the round trip if it fails.
+/// The first failure is kept in `failure`, errors other than a failed transaction are returned.
+fn side_step(
+    step: Step,
+    result: Result<Leg>,
+    failure: &mut Option<(Step, HPError)>,
+) -> Result<Option<Leg>> {
+    match result {
+        Ok(leg) => Ok(Some(leg)),
+        Err(e) if e.kind() == ErrorKind::Execution => {
+            failure.get_or_insert((step, e));
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs the swap and measures the amount the sender actually received.
+pub fn swap_and_measure(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    token_in: &ERC20,
+    token_out: &ERC20,
+    amount_in: U256,
+    fork: &mut Fork,
+) -> Result<Leg> {
+    let balance_before = venue.balance_of(token_out, config.sender, fork)?;
+    let swap = venue.swap(config.sender, token_in, token_out, amount_in, fork)?;
+    let balance_after = venue.balance_of(token_out, config.sender, fork)?;
+
+    Ok(Leg {
+        expected: swap.amount_out,
+        received: balance_after.saturating_sub(balance_before),
+        gas_used: swap.gas_used,
+        transfer_fee: tax(amount_in, swap.pool_amount_in),
+    })
+}
+
+/// Shares of the bought tokens sold by the ladder, in percent
+const LADDER: [u8; 4] = [1, 10, 50, 100];
+
+/// Sells every share of `amount` in `LADDER`, each on its own copy of the fork.
+fn ladder_sells(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    amount: U256,
+    fork: &Fork,
+) -> Result<Vec<PartialSell>> {
+    let mut sells = Vec::with_capacity(LADDER.len());
+
+    for percent in LADDER {
+        let amount = amount * U256::from(percent) / U256::from(100);
+        let (tax, error) = try_sell(venue, config, amount, &mut fork.clone())?;
+
+        sells.push(PartialSell {
+            percent,
+            amount,
+            tax,
+            error,
+        });
+    }
+
+    Ok(sells)
+}
+
+/// Sells `amount` of the token on a copy of the fork moved forward by every configured step.
+fn time_travel_sells(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    amount: U256,
+    fork: &Fork,
+) -> Result<TimeTravel> {
+    let mut sells = Vec::with_capacity(config.time_travel.len());
+
+    for &step in &config.time_travel {
+        let (blocks, seconds) = step.offset();
+        let mut later = fork.clone();
+        later.advance(blocks, seconds);
+
+        let (tax, error) = try_sell(venue, config, amount, &mut later)?;
+
+        sells.push(DelayedSell {
+            after: step,
+            blocks,
+            seconds,
+            tax,
+            error,
+        });
+    }
+
+    Ok(TimeTravel::new(sells))
+}
+
+/// Sells `amount` of the token and returns either the tax or why the sell failed.
+/// Errors other than a failed transaction are returned, as they mean the test itself failed.
+pub fn try_sell(
+    venue: &(impl TestSwap + ?Sized),
+    config: &CliConfig,
+    amount: U256,
+    fork: &mut Fork,
+) -> Result<(Option<f64>, Option<String>)> {
+    match swap_and_measure(
+        venue,
+        config,
+        &config.token,
+        &config.from_token,
+        amount,
+        fork,
+    ) {
+        Ok(leg) => Ok((Some(leg.tax()), None)),
+        Err(e) if e.kind() == ErrorKind::Execution => Ok((None, Some(e.to_string()))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Transfers `amount` of the token from the sender to `recipient`
+/// and measures the amount the recipient actually received.
+fn transfer_to(
+    config: &CliConfig,
+    recipient: Address,
+    amount: U256,
+    fork: &mut Fork,
+) -> Result<Leg> {
+    let balance_before = config.token.balance_of(recipient, config.sender, fork)?;
+    let gas_used = config
+        .token
+        .transfer(config.sender, recipient, amount, fork)?;
+    let balance_after = config.token.balance_of(recipient, config.sender, fork)?;
+
+    Ok(Leg {
+        expected: amount,
+        received: balance_after.saturating_sub(balance_before),
+        gas_used,
+        transfer_fee: 0.0,
+    })
+}
+
+/// Lets a fresh spender pull `amount` of the token from the sender with `transferFrom`
+/// and measures the amount the spender actually received.
+fn transfer_from_spender(config: &CliConfig, amount: U256, fork: &mut Fork) -> Result<Leg> {
+    let spender = random_address();
+
+    config.token.approve(config.sender, spender, amount, fork)?;
+    let balance_before = config.token.balance_of(spender, config.sender, fork)?;
+    let gas_used = config
+        .token
+        .transfer_from(spender, config.sender, spender, amount, fork)?;
+    let balance_after = config.token.balance_of(spender, config.sender, fork)?;
+
+    Ok(Leg {
+        expected: amount,
+        received: balance_after.saturating_sub(balance_before),
+        gas_used,
+        transfer_fee: 0.0,
+    })
+}
+
+/// Sells `amount` of the token through the router and measures what the sender received,
+/// in ETH if the router unwraps WETH.
+fn router_sell(
+    config: &CliConfig,
+    router: Address,
+    path: Vec<Address>,
+    amount: U256,
+    fork: &mut Fork,
+) -> Result<Leg> {
+    let to_eth = path.last() == Some(&WETH);
+    let quote_balance = |fork: &mut Fork| {
+        if to_eth {
+            eth_balance(config.sender, fork)
+        } else {
+            config
+                .from_token
+                .balance_of(config.sender, config.sender, fork)
+        }
+    };
+
+    let balance_before = quote_balance(fork)?;
+    let swap = univ2_router_swap(config.sender, router, &config.token, path, amount, fork)?;
+    let balance_after = quote_balance(fork)?;
+
+    Ok(Leg {
+        expected: swap.amount_out,
+        received: balance_after.saturating_sub(balance_before),
+        gas_used: swap.gas_used,
+        transfer_fee: tax(amount, swap.pool_amount_in),
+    })
+}
+
+/// Returns an address nobody holds the key of, so it has no balance.
+/// It is derived from random bytes, a new one on every call and every run,
+/// so a token cannot special-case it in advance.
+pub fn random_address() -> Address {
+    // The standard library keys every `RandomState` with random bytes
+    let state = RandomState::new();
+    let words: [u64; 4] = array::from_fn(|index| state.hash_one(index));
+
+    Address::from_word(keccak256(words.map(u64::to_be_bytes).concat()))
+}
+
+/// Returns the share of `expected` that was not received, in percent.
+fn tax(expected: U256, received: U256) -> f64 {
+    if expected.is_zero() || received >= expected {
+        return 0.0;
+    }
+
+    // Basis points keep two decimals of precision
+    let bps = (expected - received) * U256::from(10_000) / expected;
+    bps.to::<u64>() as f64 / 100.0
+}
+
+/// Compounds a fee and a tax taken one after the other, in percent, with two decimals.
+fn total_tax(transfer_fee: f64, tax: f64) -> f64 {
+    let kept = (100.0 - transfer_fee) * (100.0 - tax) / 100.0;
+    ((100.0 - kept) * 100.0).round() / 100.0
+}
+
+/// Prints the sender's balance of both tokens of the tested pair.
+pub fn print_balances(config: &CliConfig, fork: &mut Fork, moment: &str) -> Result<()> {
+    let from_token_balance = config
+        .from_token
+        .balance_of(config.sender, config.sender, fork)?;
+    let token_balance = config
+        .token
+        .balance_of(config.sender, config.sender, fork)?;
+
+    config.log(format!(
+        "{} balance {} swap: {}",
+        config.from_token.symbol, moment, from_token_balance
+    ));
+    config.log(format!(
+        "{} balance {} swap: {}",
+        config.token.symbol, moment, token_balance
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::HONEYPOT_TAX;
+
+    fn leg(expected: u64, received: u64, transfer_fee: f64) -> Leg {
+        Leg {
+            expected: U256::from(expected),
+            received: U256::from(received),
+            gas_used: 0,
+            transfer_fee,
+        }
+    }
+
+    #[test]
+    fn random_addresses_differ() {
+        assert_ne!(random_address(), random_address());
+    }
+
+    #[test]
+    fn tax_of_received_amount() {
+        assert_eq!(tax(U256::from(1_000), U256::from(950)), 5.0);
+        assert_eq!(tax(U256::from(1_000), U256::from(1_200)), 0.0);
+        assert_eq!(tax(U256::ZERO, U256::ZERO), 0.0);
+    }
+
+    #[test]
+    fn leg_tax_includes_transfer_fee() {
+        // The pool received 90% and paid out in full
+        assert_eq!(leg(1_000, 1_000, 10.0).tax(), 10.0);
+        // 10% on the way in, then 10% of what came out
+        assert_eq!(leg(1_000, 900, 10.0).tax(), 19.0);
+        // Only taken from what came out, e.g. through a router
+        assert_eq!(leg(1_000, 950, 0.0).tax(), 5.0);
+        // A 99% fee on the way in, e.g. after `setFee(99, 99)`, makes selling pointless
+        assert!(leg(1_000, 1_000, 99.0).tax() > HONEYPOT_TAX);
+    }
 }
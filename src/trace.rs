@@ -0,0 +1,259 @@
+use alloy::{
+    primitives::{Address, FixedBytes},
+    sol,
+    sol_types::SolCall,
+};
+use revm::{
+    interpreter::{CallInputs, CallOutcome, CallScheme},
+    Database, EvmContext, Inspector,
+};
+use serde::Serialize;
+
+use crate::revert::decode_revert;
+
+sol! {
+    function transfer(address to, uint256 amount) external returns (bool);
+    function transferFrom(address from, address to, uint256 amount) external returns (bool);
+    function approve(address spender, uint256 amount) external returns (bool);
+    function balanceOf(address account) external view returns (uint256);
+    // Uniswap V2 pair
+    function swap(uint amount0Out, uint amount1Out, address to, bytes data) external;
+    // Uniswap V3 pool
+    function swap(address recipient, bool zeroForOne, int256 amountSpecified, uint160 sqrtPriceLimitX96, bytes data) external returns (int256 amount0, int256 amount1);
+    function uniswapV3SwapCallback(int256 amount0Delta, int256 amount1Delta, bytes data) external;
+}
+
+/// Functions a swap goes through, used to name the selectors of the trace
+const KNOWN_SELECTORS: [([u8; 4], &str); 7] = [
+    (transferCall::SELECTOR, "transfer"),
+    (transferFromCall::SELECTOR, "transferFrom"),
+    (approveCall::SELECTOR, "approve"),
+    (balanceOfCall::SELECTOR, "balanceOf"),
+    (swap_0Call::SELECTOR, "swap (Uniswap V2)"),
+    (swap_1Call::SELECTOR, "swap (Uniswap V3)"),
+    (uniswapV3SwapCallbackCall::SELECTOR, "uniswapV3SwapCallback"),
+];
+
+/// A call made during the simulated transaction
+#[derive(Debug, Clone, Serialize)]
+pub struct CallFrame {
+    /// 0 for the transaction itself
+    pub depth: usize,
+    pub caller: Address,
+    /// Account whose storage the call runs on
+    pub target: Address,
+    /// Account whose code runs, differs from `target` for a `DELEGATECALL`,
+    /// e.g. a proxy token or a token handing the decision to a hidden helper
+    pub code_address: Address,
+    /// Opcode of the call, e.g. `DELEGATECALL`
+    pub scheme: &'static str,
+    pub selector: Option<FixedBytes<4>>,
+    /// Name of the function, if the selector is a known one
+    pub function: Option<String>,
+    pub success: bool,
+    /// Decoded revert data of the call, if it reverted
+    pub revert_reason: Option<String>,
+    #[serde(skip)]
+    parent: Option<usize>,
+}
+
+impl CallFrame {
+    /// Function called, by name if known
+    pub fn describe(&self) -> String {
+        match (&self.function, &self.selector) {
+            (Some(function), Some(selector)) => format!("{function} ({selector})"),
+            (None, Some(selector)) => selector.to_string(),
+            _ => "fallback".to_string(),
+        }
+    }
+
+    /// Called account, with the account whose code ran if it is another one
+    pub fn describe_target(&self) -> String {
+        if self.code_address == self.target {
+            self.target.to_string()
+        } else {
+            format!("{} ({} to {})", self.target, self.scheme, self.code_address)
+        }
+    }
+}
+
+fn scheme_name(scheme: CallScheme) -> &'static str {
+    match scheme {
+        CallScheme::Call => "CALL",
+        CallScheme::CallCode => "CALLCODE",
+        CallScheme::DelegateCall => "DELEGATECALL",
+        CallScheme::StaticCall => "STATICCALL",
+        CallScheme::ExtCall => "EXTCALL",
+        CallScheme::ExtStaticCall => "EXTSTATICCALL",
+        CallScheme::ExtDelegateCall => "EXTDELEGATECALL",
+    }
+}
+
+/// Inspector recording every call of the transaction, in the order they were made
+#[derive(Debug, Default)]
+pub struct CallTracer {
+    frames: Vec<CallFrame>,
+    /// Indexes of the calls that have not returned yet
+    stack: Vec<usize>,
+}
+
+impl<DB: Database> Inspector<DB> for CallTracer {
+    fn call(
+        &mut self,
+        _context: &mut EvmContext<DB>,
+        inputs: &mut CallInputs,
+    ) -> Option<CallOutcome> {
+        let selector = inputs
+            .input
+            .get(..4)
+            .map(|selector| FixedBytes::<4>::from_slice(selector));
+        let function = selector.and_then(|selector| {
+            KNOWN_SELECTORS
+                .iter()
+                .find(|(known, _)| *known == selector.0)
+                .map(|(_, name)| name.to_string())
+        });
+
+        self.frames.push(CallFrame {
+            depth: self.stack.len(),
+            caller: inputs.caller,
+            target: inputs.target_address,
+            code_address: inputs.bytecode_address,
+            scheme: scheme_name(inputs.scheme),
+            selector,
+            function,
+            // Updated once the call returns
+            success: true,
+            revert_reason: None,
+            parent: self.stack.last().copied(),
+        });
+        self.stack.push(self.frames.len() - 1);
+
+        None
+    }
+
+    fn call_end(
+        &mut self,
+        _context: &mut EvmContext<DB>,
+        _inputs: &CallInputs,
+        outcome: CallOutcome,
+    ) -> CallOutcome {
+        if let Some(index) = self.stack.pop() {
+            let frame = &mut self.frames[index];
+            frame.success = outcome.result.is_ok();
+            if outcome.result.is_revert() {
+                frame.revert_reason = Some(decode_revert(&outcome.result.output));
+            }
+        }
+
+        outcome
+    }
+}
+
+impl CallTracer {
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    /// Returns the call the failure of the transaction originated from.
+    ///
+    /// Starting from the transaction, follows the last failed sub call, as that is
+    /// the one whose revert bubbled up, until a call without failed sub calls is reached.
+    pub fn failure_origin(&self) -> Option<&CallFrame> {
+        let mut origin = self
+            .frames
+            .iter()
+            .position(|frame| frame.parent.is_none())?;
+
+        if self.frames[origin].success {
+            return None;
+        }
+
+        while let Some(child) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.parent == Some(origin) && !frame.success)
+        {
+            origin = child;
+        }
+
+        Some(&self.frames[origin])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records a call to `target` under `parent`, returning its index
+    fn call(tracer: &mut CallTracer, parent: Option<usize>, target: u8, success: bool) -> usize {
+        let depth = parent.map_or(0, |parent| tracer.frames[parent].depth + 1);
+        tracer.frames.push(CallFrame {
+            depth,
+            caller: Address::ZERO,
+            target: Address::with_last_byte(target),
+            code_address: Address::with_last_byte(target),
+            scheme: "CALL",
+            selector: None,
+            function: None,
+            success,
+            revert_reason: None,
+            parent,
+        });
+        tracer.frames.len() - 1
+    }
+
+    #[test]
+    fn nested_revert() {
+        let mut tracer = CallTracer::default();
+        let router = call(&mut tracer, None, 1, false);
+        let pair = call(&mut tracer, Some(router), 2, false);
+        call(&mut tracer, Some(pair), 3, true);
+        let token = call(&mut tracer, Some(pair), 4, false);
+        call(&mut tracer, Some(token), 5, false);
+
+        assert_eq!(
+            tracer.failure_origin().map(|frame| frame.target),
+            Some(Address::with_last_byte(5))
+        );
+    }
+
+    #[test]
+    fn caught_failure_before_the_revert() {
+        let mut tracer = CallTracer::default();
+        let token = call(&mut tracer, None, 1, false);
+        // Caught by the token, e.g. with `try`, before the call that reverted
+        call(&mut tracer, Some(token), 2, false);
+        call(&mut tracer, Some(token), 3, false);
+
+        assert_eq!(
+            tracer.failure_origin().map(|frame| frame.target),
+            Some(Address::with_last_byte(3))
+        );
+    }
+
+    #[test]
+    fn successful_transaction() {
+        let mut tracer = CallTracer::default();
+        let token = call(&mut tracer, None, 1, true);
+        // Caught, so the transaction still succeeded
+        call(&mut tracer, Some(token), 2, false);
+
+        assert!(tracer.failure_origin().is_none());
+    }
+
+    #[test]
+    fn describes_delegatecall_code() {
+        let mut tracer = CallTracer::default();
+        let index = call(&mut tracer, None, 1, true);
+        let frame = &mut tracer.frames[index];
+        assert_eq!(frame.describe_target(), frame.target.to_string());
+
+        frame.code_address = Address::with_last_byte(2);
+        frame.scheme = "DELEGATECALL";
+        assert_eq!(
+            frame.describe_target(),
+            format!("{} (DELEGATECALL to {})", frame.target, frame.code_address)
+        );
+    }
+}
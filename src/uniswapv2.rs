@@ -1,5 +1,6 @@
 use alloy::{
-    primitives::{keccak256, Address},
+    eips::BlockId,
+    primitives::Address,
     providers::{Provider, RootProvider},
     rpc::types::TransactionRequest,
     sol,
@@ -8,21 +9,20 @@ use alloy::{
 };
 // use anyhow::Result;
 use revm::{
-    primitives::{address, AccountInfo, Bytes, ExecutionResult, Output, TxKind, U256},
+    inspector_handle_register,
+    primitives::{address, Bytes, ExecutionResult, Output, TxKind, U256},
     Evm,
 };
 
 use crate::{
-    cli::CliConfig,
-    erc20::ERC20,
+    erc20::{eth_balance, ERC20, WETH},
     error::{HPError, Result},
-    test_swap::TestSwap,
-    AlloyCacheDB,
+    fork::Fork,
+    test_swap::{SwapResult, TestSwap},
+    trace::CallTracer,
 };
 
-const UNIV2_FACTORY: Address = address!("5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f");
 const UNIV2_ROUTER: Address = address!("7a250d5630b4cf539739df2c5dacb4c659f2488d");
-const WETH: Address = address!("c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
 
 sol! {
     function balanceOf(address account) public returns (uint256);
@@ -30,113 +30,125 @@ sol! {
     function getReserves() external view returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast);
     function getAmountOut(uint amountIn, uint reserveIn, uint reserveOut) external pure returns (uint amountOut);
     function swap(uint amount0Out, uint amount1Out, address target, bytes callback) external;
+    function getAmountsOut(uint amountIn, address[] path) external view returns (uint[] amounts);
+    function swapExactETHForTokensSupportingFeeOnTransferTokens(uint amountOutMin, address[] path, address to, uint deadline) external payable;
+    function swapExactTokensForETHSupportingFeeOnTransferTokens(uint amountIn, uint amountOutMin, address[] path, address to, uint deadline) external;
+    function swapExactTokensForTokensSupportingFeeOnTransferTokens(uint amountIn, uint amountOutMin, address[] path, address to, uint deadline) external;
 }
 
-#[derive(Debug)]
+/// A Uniswap V2 deployment or one of its forks sharing the same pair interface and 0.3% fee.
+#[derive(Debug, Clone, Copy)]
+pub struct V2Fork {
+    pub name: &'static str,
+    pub factory: Address,
+    /// `UniswapV2Router02` deployment of the fork
+    pub router: Address,
+}
+
+pub const UNISWAP_V2: V2Fork = V2Fork {
+    name: "Uniswap V2",
+    factory: address!("5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"),
+    router: UNIV2_ROUTER,
+};
+
+pub const SUSHISWAP: V2Fork = V2Fork {
+    name: "SushiSwap",
+    factory: address!("C0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"),
+    router: address!("d9e1cE17f2641f24aE83637ab66a2cca9C378B9F"),
+};
+
+#[derive(Debug, Clone)]
 pub struct UniV2Pair {
     pub address: Address,
     pub token0: Address,
     pub token1: Address,
 }
 
-pub struct UniswapV2;
+pub struct UniswapV2 {
+    pub fork: V2Fork,
+    pub pair: UniV2Pair,
+    /// Swap through the router instead of the pair, see `univ2_router_swap`
+    pub via_router: bool,
+}
 
 impl UniswapV2 {
-    pub fn new() -> Self {
-        Self
+    pub fn new(fork: V2Fork, pair: UniV2Pair, via_router: bool) -> Self {
+        Self {
+            fork,
+            pair,
+            via_router,
+        }
     }
 }
 
 impl TestSwap for UniswapV2 {
-    async fn test_swap(&self, config: &CliConfig, db: &mut AlloyCacheDB) -> Result<()> {
-        let pair = get_pair(&config.token.address, &WETH, &config.client).await?;
-
-        // 1. Add WETH to account
-        let weth_balance_slot = U256::from(3);
-        let one_eth = U256::from(10_u128.pow(18));
-        let weth_user_balance_slot = keccak256((config.sender, weth_balance_slot).abi_encode());
-
-        db.insert_account_storage(WETH, weth_user_balance_slot.into(), one_eth)
-            .expect("Failed to insert account storage");
-
-        db.insert_account_info(
-            config.sender,
-            AccountInfo {
-                balance: one_eth,
-                ..Default::default()
-            },
-        );
-
-        if config.logs {
-            let from_token_balance_before: U256 =
-                config
-                    .from_token
-                    .balance_of(config.sender, config.sender, db)?;
-            let token_balance_before: U256 =
-                config.token.balance_of(config.sender, config.sender, db)?;
-
-            println!(
-                "{} balance before swap: {}",
-                config.from_token.symbol, from_token_balance_before
-            );
-            println!(
-                "{} balance before swap: {}",
-                config.token.symbol, token_balance_before
-            );
-        }
+    fn name(&self) -> String {
+        self.fork.name.to_string()
+    }
+
+    fn pool(&self) -> Address {
+        self.pair.address
+    }
+
+    fn router_path(&self, token_in: &ERC20, token_out: &ERC20) -> Option<(Address, Vec<Address>)> {
+        Some((self.fork.router, vec![token_in.address, token_out.address]))
+    }
 
-        let amount_in = one_eth.div_ceil(U256::from(10));
-        let reserves = get_univ2_reserves(pair.address, config.sender, db)?;
-
-        // 2. Swap WETH for Token
-        let amount_out = univ2_swap(
-            config.sender,
-            &pair,
-            config.from_token.clone(),
-            amount_in,
-            reserves,
-            db,
-        )?;
-
-        // 3. Swap Token for WETH
-        //    this is what shows if the token is a honeypot or not.
-        let reserves = get_univ2_reserves(pair.address, config.sender, db)?;
-        univ2_swap(
-            config.sender,
-            &pair,
-            config.token.clone(),
-            amount_out,
-            reserves,
-            db,
-        )?;
-
-        if config.logs {
-            let from_token_balance_after: U256 =
-                config
-                    .from_token
-                    .balance_of(config.sender, config.sender, db)?;
-            let token_balance_after: U256 =
-                config.token.balance_of(config.sender, config.sender, db)?;
-
-            println!(
-                "{} balance after swap: {}",
-                config.from_token.symbol, from_token_balance_after
-            );
-            println!(
-                "{} balance after swap: {}",
-                config.token.symbol, token_balance_after
-            );
+    fn balance_of(&self, token: &ERC20, owner: Address, fork: &mut Fork) -> Result<U256> {
+        router_balance_of(self.via_router, token, owner, fork)
+    }
+
+    fn swap(
+        &self,
+        sender: Address,
+        token_in: &ERC20,
+        token_out: &ERC20,
+        amount_in: U256,
+        fork: &mut Fork,
+    ) -> Result<SwapResult> {
+        if self.via_router {
+            let path = vec![token_in.address, token_out.address];
+            return univ2_router_swap(sender, self.fork.router, token_in, path, amount_in, fork);
         }
 
-        Ok(())
+        univ2_swap(sender, &self.pair, token_in.clone(), amount_in, fork)
+    }
+}
+
+/// Through the router, WETH is paid and received as ETH
+pub fn router_balance_of(
+    via_router: bool,
+    token: &ERC20,
+    owner: Address,
+    fork: &mut Fork,
+) -> Result<U256> {
+    if via_router && token.address == WETH {
+        eth_balance(owner, fork)
+    } else {
+        token.balance_of(owner, owner, fork)
     }
 }
 
 pub async fn get_pair(
+    fork: &V2Fork,
     token0: &Address,
     token1: &Address,
+    block_number: u64,
     client: &RootProvider<Http<Client>>,
 ) -> Result<UniV2Pair> {
+    find_pair(fork, token0, token1, block_number, client)
+        .await?
+        .ok_or_else(|| HPError::no_liquidity(format!("Pair does not exist on {}", fork.name)))
+}
+
+/// Returns the pair of the two tokens, or `None` if the factory had not deployed it at the block.
+pub async fn find_pair(
+    fork: &V2Fork,
+    token0: &Address,
+    token1: &Address,
+    block_number: u64,
+    client: &RootProvider<Http<Client>>,
+) -> Result<Option<UniV2Pair>> {
     sol! {
       function getPair(address,address) public view returns (address);
     }
@@ -149,22 +161,20 @@ pub async fn get_pair(
 
     let pair = client
         .call(&TransactionRequest {
-            to: Some(TxKind::Call(UNIV2_FACTORY)),
+            to: Some(TxKind::Call(fork.factory)),
             input: pair_calldata.into(),
             ..Default::default()
         })
+        .block(BlockId::number(block_number))
         .await
-        .map_err(HPError::error)?;
+        .map_err(HPError::rpc_error)?;
 
     let pair_res = getPairCall::abi_decode_returns(&pair, true)
         .map_err(HPError::error)?
         ._0;
 
     if pair_res == Address::ZERO {
-        return Err(HPError::new(
-            "Pair does not exist on Uniswap V2".to_string(),
-            None,
-        ));
+        return Ok(None);
     }
 
     let (token0, token1) = if *token0 < *token1 {
@@ -173,22 +183,19 @@ pub async fn get_pair(
         (*token1, *token0)
     };
 
-    Ok(UniV2Pair {
+    Ok(Some(UniV2Pair {
         address: pair_res,
         token0,
         token1,
-    })
+    }))
 }
 
-pub fn get_univ2_reserves(
-    pair: Address,
-    sender: Address,
-    alloy_db: &mut AlloyCacheDB,
-) -> Result<(U256, U256)> {
+pub fn get_univ2_reserves(pair: Address, sender: Address, fork: &mut Fork) -> Result<(U256, U256)> {
     let calldata = getReservesCall {}.abi_encode();
 
     let mut evm = Evm::builder()
-        .with_db(alloy_db)
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
         .modify_tx_env(|tx| {
             // For consistency, we use the same sender for all calls
             tx.caller = sender;
@@ -197,7 +204,7 @@ pub fn get_univ2_reserves(
         })
         .build();
 
-    let tx = evm.transact().map_err(HPError::error)?;
+    let tx = evm.transact().map_err(HPError::evm_error)?;
     let result = tx.result;
 
     let value = match result {
@@ -206,9 +213,9 @@ pub fn get_univ2_reserves(
             ..
         } => value,
         result => {
-            return Err(HPError::new(
-                format!("'getReserves' execution failed: {result:?}"),
-                None,
+            return Err(HPError::execution_failed(
+                "'getReserves' execution failed",
+                &result,
             ))
         }
     };
@@ -226,9 +233,14 @@ pub fn univ2_swap(
     pair: &UniV2Pair,
     token_in: ERC20,
     amount_in: U256,
-    reserves: (U256, U256),
-    cache_db: &mut AlloyCacheDB,
-) -> Result<U256> {
+    fork: &mut Fork,
+) -> Result<SwapResult> {
+    // The token's transfer logic runs here, so its gas is part of the swap
+    let transfer_gas = token_in.transfer(sender, pair.address, amount_in, fork)?;
+
+    // Like the router, the reserves are read after the transfer, as tax tokens often swap
+    // their collected fees through the same pair inside it
+    let reserves = get_univ2_reserves(pair.address, sender, fork)?;
     let is_token_0_in = pair.token0 == token_in.address;
     let (reserve_in, reserve_out) = if is_token_0_in {
         reserves
@@ -236,8 +248,11 @@ pub fn univ2_swap(
         (reserves.1, reserves.0)
     };
 
-    token_in.transfer(sender, pair.address, amount_in, cache_db)?;
-    let amount_out = get_univ2_amount_out(amount_in, reserve_in, reserve_out, sender, cache_db)?;
+    // Like the router's `SupportingFeeOnTransferTokens` functions, the input is what the pair
+    // actually received, as tokens taking a fee on transfer send less than `amount_in`
+    let pair_balance = token_in.balance_of(pair.address, sender, fork)?;
+    let pair_amount_in = pair_balance.saturating_sub(reserve_in);
+    let amount_out = get_univ2_amount_out(pair_amount_in, reserve_in, reserve_out, sender, fork)?;
 
     let amount0_out = if is_token_0_in {
         U256::from(0)
@@ -259,27 +274,170 @@ pub fn univ2_swap(
     .abi_encode();
 
     let mut evm = Evm::builder()
-        .with_db(cache_db)
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
+        .with_external_context(CallTracer::default())
         .modify_tx_env(|tx| {
             tx.caller = sender;
             tx.transact_to = TxKind::Call(pair.address);
             tx.data = calldata.into();
         })
+        .append_handler_register(inspector_handle_register)
         .build();
 
-    let tx = evm.transact_commit().map_err(HPError::error)?;
+    let tx = evm.transact_commit().map_err(HPError::evm_error)?;
 
-    match tx {
-        ExecutionResult::Success { .. } => {}
+    let gas_used = match tx {
+        ExecutionResult::Success { gas_used, .. } => gas_used,
         result => {
-            return Err(HPError::new(
-                format!("'swap' execution failed on Uniswap V2 pair: {result:?}"),
-                None,
-            ))
+            return Err(HPError::execution_failed(
+                "'swap' execution failed on Uniswap V2 pair",
+                &result,
+            )
+            .with_trace(&evm.context.external))
+        }
+    };
+
+    Ok(SwapResult {
+        amount_out,
+        pool_amount_in: pair_amount_in,
+        gas_used: transfer_gas + gas_used,
+    })
+}
+
+/// Swaps through the router like a wallet would. Pays with ETH if `path` starts with WETH,
+/// otherwise the router is approved and pulls the tokens with `transferFrom`.
+/// Ends with ETH if `path` ends with WETH.
+pub fn univ2_router_swap(
+    sender: Address,
+    router: Address,
+    token_in: &ERC20,
+    path: Vec<Address>,
+    amount_in: U256,
+    fork: &mut Fork,
+) -> Result<SwapResult> {
+    let eth_in = path.first() == Some(&WETH);
+
+    if !eth_in {
+        token_in.approve(sender, router, amount_in, fork)?;
+
+        // Some tokens silently approve less than asked, so the router cannot pull the whole amount
+        let allowance = token_in.allowance(sender, router, sender, fork)?;
+        if allowance < amount_in {
+            return Err(HPError::execution(format!(
+                "'approve' of {} only set an allowance of {}",
+                amount_in, allowance
+            )));
         }
     }
 
-    Ok(amount_out)
+    let amount_out = get_univ2_amounts_out(router, amount_in, path.clone(), sender, fork)?;
+
+    let calldata = if eth_in {
+        swapExactETHForTokensSupportingFeeOnTransferTokensCall {
+            amountOutMin: U256::from(0),
+            path,
+            to: sender,
+            deadline: U256::MAX,
+        }
+        .abi_encode()
+    } else if path.last() == Some(&WETH) {
+        swapExactTokensForETHSupportingFeeOnTransferTokensCall {
+            amountIn: amount_in,
+            amountOutMin: U256::from(0),
+            path,
+            to: sender,
+            deadline: U256::MAX,
+        }
+        .abi_encode()
+    } else {
+        swapExactTokensForTokensSupportingFeeOnTransferTokensCall {
+            amountIn: amount_in,
+            amountOutMin: U256::from(0),
+            path,
+            to: sender,
+            deadline: U256::MAX,
+        }
+        .abi_encode()
+    };
+    let value = if eth_in { amount_in } else { U256::from(0) };
+
+    let mut evm = Evm::builder()
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
+        .with_external_context(CallTracer::default())
+        .modify_tx_env(|tx| {
+            tx.caller = sender;
+            tx.transact_to = TxKind::Call(router);
+            tx.data = calldata.into();
+            tx.value = value;
+        })
+        .append_handler_register(inspector_handle_register)
+        .build();
+
+    let tx = evm.transact_commit().map_err(HPError::evm_error)?;
+
+    let gas_used = match tx {
+        ExecutionResult::Success { gas_used, .. } => gas_used,
+        result => {
+            return Err(HPError::execution_failed(
+                "Swap execution failed on Uniswap V2 router",
+                &result,
+            )
+            .with_trace(&evm.context.external))
+        }
+    };
+
+    Ok(SwapResult {
+        amount_out,
+        // The router only reports what comes out
+        pool_amount_in: amount_in,
+        gas_used,
+    })
+}
+
+/// Amount out of the last swap of `path`, before any token tax is taken.
+fn get_univ2_amounts_out(
+    router: Address,
+    amount_in: U256,
+    path: Vec<Address>,
+    sender: Address,
+    fork: &mut Fork,
+) -> Result<U256> {
+    let calldata = getAmountsOutCall {
+        amountIn: amount_in,
+        path,
+    }
+    .abi_encode();
+
+    let mut evm = Evm::builder()
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
+        .modify_tx_env(|tx| {
+            tx.caller = sender;
+            tx.transact_to = TxKind::Call(router);
+            tx.data = calldata.into();
+        })
+        .build();
+
+    let tx = evm.transact().map_err(HPError::evm_error)?;
+
+    let value = match tx.result {
+        ExecutionResult::Success {
+            output: Output::Call(value),
+            ..
+        } => value,
+        result => {
+            return Err(HPError::execution_failed(
+                "'getAmountsOut' execution failed",
+                &result,
+            ))
+        }
+    };
+
+    let amounts = <Vec<U256>>::abi_decode(&value, false).map_err(HPError::error)?;
+
+    Ok(amounts.last().copied().unwrap_or_default())
 }
 
 pub fn get_univ2_amount_out(
@@ -287,7 +445,7 @@ pub fn get_univ2_amount_out(
     reserve_in: U256,
     reserve_out: U256,
     sender: Address,
-    cache_db: &mut AlloyCacheDB,
+    fork: &mut Fork,
 ) -> Result<U256> {
     let calldata = getAmountOutCall {
         amountIn: amount_in,
@@ -297,7 +455,8 @@ pub fn get_univ2_amount_out(
     .abi_encode();
 
     let mut evm = Evm::builder()
-        .with_db(cache_db)
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
         .modify_tx_env(|tx| {
             tx.caller = sender;
             tx.transact_to = TxKind::Call(UNIV2_ROUTER);
@@ -305,7 +464,7 @@ pub fn get_univ2_amount_out(
         })
         .build();
 
-    let tx = evm.transact().map_err(HPError::error)?;
+    let tx = evm.transact().map_err(HPError::evm_error)?;
     let result = tx.result;
 
     let value = match result {
@@ -314,9 +473,9 @@ pub fn get_univ2_amount_out(
             ..
         } => value,
         result => {
-            return Err(HPError::new(
-                format!("'getAmountOut' execution failed: {result:?}"),
-                None,
+            return Err(HPError::execution_failed(
+                "'getAmountOut' execution failed",
+                &result,
             ))
         }
     };
@@ -1,187 +1,278 @@
-// // sqrtPriceLimitX96 == 0
-// //     ? (zeroForOne ? TickMath.MIN_SQRT_RATIO + 1 : TickMath.MAX_SQRT_RATIO - 1)
-// //     : sqrtPriceLimitX96,
-
-// // /// @dev The minimum tick that may be passed to #getSqrtRatioAtTick computed from log base 1.0001 of 2**-128
-// // int24 internal constant MIN_TICK = -887272;
-// // /// @dev The maximum tick that may be passed to #getSqrtRatioAtTick computed from log base 1.0001 of 2**128
-// // int24 internal constant MAX_TICK = -MIN_TICK;
-
-// use alloy::primitives::aliases::U24;
-// use alloy::primitives::U160;
-// use alloy::sol;
-// use alloy::sol_types::{SolCall, SolValue};
-// use anyhow::{anyhow, Result};
-// use revm::primitives::{address, keccak256, AccountInfo, Address, ExecutionResult, TxKind, U256};
-// use revm::Evm;
-
-// use crate::cli::DEFAULT_ACC;
-// use crate::revm_actions::balance_of;
-// use crate::{AlloyCacheDB, WETH};
-
-// const UNIV3_ROUTER: Address = address!("E592427A0AEce92De3Edee1F18E0157C05861564");
-
-// sol! {
-//     function swap(address recipient, bool zeroForOne, int256 amountSpecified, uint160 sqrtPriceLimitX96, bytes calldata data) external returns (int256 amount0, int256 amount1);
-
-//     struct ExactInputSingleParams {
-//         address tokenIn;
-//         address tokenOut;
-//         uint24 fee;
-//         address recipient;
-//         uint256 deadline;
-//         uint256 amountIn;
-//         uint256 amountOutMinimum;
-//         uint160 sqrtPriceLimitX96;
-//     }
-//     function exactInputSingle(ExactInputSingleParams calldata params) external returns (uint256 amountOut);
-
-//     function approve(address spender, uint256 amount) external returns (bool);
-// }
-
-// pub fn test_swap(db: &mut AlloyCacheDB) -> Result<()> {
-//     let sender = DEFAULT_ACC;
-//     // USDC/WETH
-//     let weth_usdc_500_pool = address!("88e6a0c2ddd26feeb64f039a2c41296fcb3f5640");
-//     let usdc = address!("a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
-//     // Swap from WETH to USDC
-//     let zero_for_one = false;
-//     // 0.1 WETH
-//     // let amount_specified = I256::try_from(1 * 10u128.pow(18) / 10).unwrap();
-//     let amount_specified = U256::try_from(1 * 10u128.pow(18) / 10).unwrap();
-
-//     // Adding WETH to sender
-//     let weth_balance_slot = U256::from(3);
-//     let one_eth = U256::from(10_u128.pow(18));
-//     let weth_user_balance_slot = keccak256((sender, weth_balance_slot).abi_encode());
-//     db.insert_account_storage(WETH, weth_user_balance_slot.into(), one_eth)
-//         .expect("Failed to insert account storage");
-//     db.insert_account_info(
-//         sender,
-//         AccountInfo {
-//             balance: one_eth,
-//             ..Default::default()
-//         },
-//     );
-
-//     // transfer(sender, weth_usdc_500_pool, one_eth, WETH, db)?;
-//     approve(WETH, sender, one_eth, db)?;
-
-//     univ3_swap(
-//         weth_usdc_500_pool,
-//         usdc,
-//         sender,
-//         zero_for_one,
-//         amount_specified,
-//         db,
-//     )?;
-
-//     let usdc_balance_after = balance_of(usdc, sender, sender, db)?;
-//     println!("USDC balance after swap: {}", usdc_balance_after);
-
-//     Ok(())
-// }
-
-// fn univ3_swap(
-//     pool: Address,
-//     token: Address,
-//     sender: Address,
-//     zero_for_one: bool,
-//     amount_specified: U256,
-//     db: &mut AlloyCacheDB,
-// ) -> Result<()> {
-//     let min_sqrt_ratio: U160 = U160::from(4295128739_u64);
-//     let max_sqrt_ratio: U160 = "1461446703485210103287273052203988822378723970342"
-//         .parse()
-//         .unwrap();
-
-//     let sqrt_price_limit_x96 = if zero_for_one {
-//         min_sqrt_ratio.checked_add(U160::from(1)).unwrap()
-//     } else {
-//         max_sqrt_ratio.checked_sub(U160::from(1)).unwrap()
-//     };
-
-//     // let amount_specified = if zero_for_one {
-//     //     amount_specified
-//     // } else {
-//     //     -amount_specified
-//     // };
-
-//     println!("sqrt_price_limit_x96: {}", sqrt_price_limit_x96);
-//     println!("amount_specified: {}", amount_specified);
-
-//     // let calldata = swapCall {
-//     //     recipient: DEFAULT_ACC,
-//     //     zeroForOne: zero_for_one,
-//     //     amountSpecified: amount_specified,
-//     //     sqrtPriceLimitX96: sqrt_price_limit_x96,
-//     //     data: Bytes::default(),
-//     // }
-//     // .abi_encode();
-//     let params = ExactInputSingleParams {
-//         tokenIn: WETH,
-//         tokenOut: token,
-//         fee: U24::from(500),
-//         recipient: sender,
-//         deadline: U256::from(1111111111),
-//         amountIn: amount_specified,
-//         amountOutMinimum: U256::from(0),
-//         // sqrtPriceLimitX96: sqrt_price_limit_x96,
-//         sqrtPriceLimitX96: U160::from(0),
-//     };
-
-//     let calldata = exactInputSingleCall { params }.abi_encode();
-
-//     let token_balance_before = balance_of(token, sender, sender, db)?;
-//     println!("USDC balance before swap: {}", token_balance_before);
-
-//     let mut evm = Evm::builder()
-//         .with_db(db)
-//         .modify_tx_env(|tx| {
-//             tx.caller = sender;
-//             tx.transact_to = TxKind::Call(UNIV3_ROUTER);
-//             tx.data = calldata.into();
-//             // tx.gas_limit = 100_000_000;
-//         })
-//         .build();
-
-//     let tx = evm
-//         .transact_commit()
-//         .map_err(|e| anyhow!("Failed to execute swap call on Uniswap V3 pair: {e}"))?;
-
-//     match tx {
-//         ExecutionResult::Success { .. } => Ok(()),
-//         result => {
-//             println!("Swap failed: {:?}", result);
-//             return Err(anyhow!(
-//                 "'swap' execution failed on Uniswap V2 pair: {result:?}"
-//             ));
-//         }
-//     }
-// }
-
-// fn approve(token: Address, sender: Address, amount: U256, db: &mut AlloyCacheDB) -> Result<()> {
-//     let calldata = approveCall {
-//         spender: UNIV3_ROUTER,
-//         amount,
-//     }
-//     .abi_encode();
-
-//     let mut evm = Evm::builder()
-//         .with_db(db)
-//         .modify_tx_env(|tx| {
-//             tx.caller = sender;
-//             tx.transact_to = TxKind::Call(token);
-//             tx.data = calldata.into();
-//         })
-//         .build();
-
-//     let tx = evm
-//         .transact_commit()
-//         .map_err(|e| anyhow!("Failed to execute transfer call: {e}"))?;
-
-//     match tx {
-//         ExecutionResult::Success { .. } => return Ok(()),
-//         result => return Err(anyhow!("'approve' execution failed: {result:?}")),
-//     };
-// }
+use alloy::{
+    eips::BlockId,
+    primitives::{
+        aliases::{U160, U24},
+        Address,
+    },
+    providers::{Provider, RootProvider},
+    rpc::types::TransactionRequest,
+    sol,
+    sol_types::{SolCall, SolValue},
+    transports::http::{Client, Http},
+};
+use revm::{
+    inspector_handle_register,
+    primitives::{address, ExecutionResult, Output, TxKind, U256},
+    Evm,
+};
+
+use crate::{
+    erc20::ERC20,
+    error::{HPError, Result},
+    fork::Fork,
+    test_swap::{SwapResult, TestSwap},
+    trace::CallTracer,
+};
+
+const UNIV3_FACTORY: Address = address!("1F98431c8aD98523631AE4a59f267346ea31F984");
+const UNIV3_ROUTER: Address = address!("E592427A0AEce92De3Edee1F18E0157C05861564");
+/// All fee tiers enabled on the Uniswap V3 factory, in hundredths of a bip
+const FEE_TIERS: [u32; 4] = [100, 500, 3000, 10000];
+
+sol! {
+    struct ExactInputSingleParams {
+        address tokenIn;
+        address tokenOut;
+        uint24 fee;
+        address recipient;
+        uint256 deadline;
+        uint256 amountIn;
+        uint256 amountOutMinimum;
+        uint160 sqrtPriceLimitX96;
+    }
+    function exactInputSingle(ExactInputSingleParams calldata params) external payable returns (uint256 amountOut);
+    function liquidity() external view returns (uint128);
+    function tickSpacing() external view returns (int24);
+    function slot0() external view returns (uint160 sqrtPriceX96, int24 tick, uint16 observationIndex, uint16 observationCardinality, uint16 observationCardinalityNext, uint8 feeProtocol, bool unlocked);
+}
+
+#[derive(Debug, Clone)]
+pub struct Slot0 {
+    pub sqrt_price_x96: U160,
+    pub tick: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct UniV3Pool {
+    pub address: Address,
+    pub token0: Address,
+    pub token1: Address,
+    pub fee: u32,
+    pub tick_spacing: i32,
+    /// In-range liquidity of the pool
+    pub liquidity: u128,
+    pub slot0: Slot0,
+}
+
+pub struct UniswapV3 {
+    pub pool: UniV3Pool,
+}
+
+impl UniswapV3 {
+    pub fn new(pool: UniV3Pool) -> Self {
+        Self { pool }
+    }
+}
+
+impl TestSwap for UniswapV3 {
+    fn name(&self) -> String {
+        format!("Uniswap V3 ({}%)", self.pool.fee as f64 / 10_000.0)
+    }
+
+    fn pool(&self) -> Address {
+        self.pool.address
+    }
+
+    fn swap(
+        &self,
+        sender: Address,
+        token_in: &ERC20,
+        token_out: &ERC20,
+        amount_in: U256,
+        fork: &mut Fork,
+    ) -> Result<SwapResult> {
+        univ3_swap(sender, &self.pool, token_in, token_out, amount_in, fork)
+    }
+}
+
+/// Looks up the pool for every fee tier and returns the one with the deepest in-range liquidity.
+pub async fn get_pool(
+    token0: &Address,
+    token1: &Address,
+    block_number: u64,
+    client: &RootProvider<Http<Client>>,
+) -> Result<UniV3Pool> {
+    get_pools(token0, token1, block_number, client)
+        .await?
+        .into_iter()
+        .max_by_key(|pool| pool.liquidity)
+        .ok_or_else(|| HPError::no_liquidity("Pool does not exist on Uniswap V3".to_string()))
+}
+
+/// Returns the pools of every fee tier deployed for the two tokens at the block.
+pub async fn get_pools(
+    token0: &Address,
+    token1: &Address,
+    block_number: u64,
+    client: &RootProvider<Http<Client>>,
+) -> Result<Vec<UniV3Pool>> {
+    let mut pools = Vec::new();
+
+    for fee in FEE_TIERS {
+        if let Some(pool) = get_pool_with_fee(token0, token1, fee, block_number, client).await? {
+            pools.push(pool);
+        }
+    }
+
+    Ok(pools)
+}
+
+/// Returns the pool of the given fee tier with its state at the block,
+/// or `None` if the factory had not deployed it yet.
+pub async fn get_pool_with_fee(
+    token0: &Address,
+    token1: &Address,
+    fee: u32,
+    block_number: u64,
+    client: &RootProvider<Http<Client>>,
+) -> Result<Option<UniV3Pool>> {
+    sol! {
+      function getPool(address,address,uint24) public view returns (address);
+    }
+
+    let pool_calldata = getPoolCall {
+        _0: *token0,
+        _1: *token1,
+        _2: U24::from(fee),
+    }
+    .abi_encode();
+
+    let pool = client
+        .call(&TransactionRequest {
+            to: Some(TxKind::Call(UNIV3_FACTORY)),
+            input: pool_calldata.into(),
+            ..Default::default()
+        })
+        .block(BlockId::number(block_number))
+        .await
+        .map_err(HPError::rpc_error)?;
+
+    let pool_res = getPoolCall::abi_decode_returns(&pool, true)
+        .map_err(HPError::error)?
+        ._0;
+
+    if pool_res == Address::ZERO {
+        return Ok(None);
+    }
+
+    let liquidity = call_pool(pool_res, liquidityCall {}, block_number, client)
+        .await?
+        ._0;
+    let tick_spacing = call_pool(pool_res, tickSpacingCall {}, block_number, client)
+        .await?
+        ._0;
+    let slot0 = call_pool(pool_res, slot0Call {}, block_number, client).await?;
+
+    let (token0, token1) = if *token0 < *token1 {
+        (*token0, *token1)
+    } else {
+        (*token1, *token0)
+    };
+
+    Ok(Some(UniV3Pool {
+        address: pool_res,
+        token0,
+        token1,
+        fee,
+        tick_spacing: tick_spacing.as_i32(),
+        liquidity,
+        slot0: Slot0 {
+            sqrt_price_x96: slot0.sqrtPriceX96,
+            tick: slot0.tick.as_i32(),
+        },
+    }))
+}
+
+async fn call_pool<C: SolCall>(
+    pool: Address,
+    call: C,
+    block_number: u64,
+    client: &RootProvider<Http<Client>>,
+) -> Result<C::Return> {
+    let res = client
+        .call(&TransactionRequest {
+            to: Some(TxKind::Call(pool)),
+            input: call.abi_encode().into(),
+            ..Default::default()
+        })
+        .block(BlockId::number(block_number))
+        .await
+        .map_err(HPError::rpc_error)?;
+
+    C::abi_decode_returns(&res, true).map_err(HPError::error)
+}
+
+/// Swaps `amount_in` of `token_in` for `token_out` through the Uniswap V3 `SwapRouter`.
+/// The amount out is the one reported by the router.
+pub fn univ3_swap(
+    sender: Address,
+    pool: &UniV3Pool,
+    token_in: &ERC20,
+    token_out: &ERC20,
+    amount_in: U256,
+    fork: &mut Fork,
+) -> Result<SwapResult> {
+    token_in.approve(sender, UNIV3_ROUTER, amount_in, fork)?;
+
+    let params = ExactInputSingleParams {
+        tokenIn: token_in.address,
+        tokenOut: token_out.address,
+        fee: U24::from(pool.fee),
+        recipient: sender,
+        deadline: U256::MAX,
+        amountIn: amount_in,
+        amountOutMinimum: U256::from(0),
+        // No price limit, the whole amount is swapped
+        sqrtPriceLimitX96: U160::from(0),
+    };
+
+    let calldata = exactInputSingleCall { params }.abi_encode();
+
+    let mut evm = Evm::builder()
+        .with_db(&mut fork.db)
+        .with_env(fork.env.clone())
+        .with_external_context(CallTracer::default())
+        .modify_tx_env(|tx| {
+            tx.caller = sender;
+            tx.transact_to = TxKind::Call(UNIV3_ROUTER);
+            tx.data = calldata.into();
+        })
+        .append_handler_register(inspector_handle_register)
+        .build();
+
+    let tx = evm.transact_commit().map_err(HPError::evm_error)?;
+
+    let (value, gas_used) = match tx {
+        ExecutionResult::Success {
+            output: Output::Call(value),
+            gas_used,
+            ..
+        } => (value, gas_used),
+        result => {
+            return Err(HPError::execution_failed(
+                "'exactInputSingle' execution failed on Uniswap V3 router",
+                &result,
+            )
+            .with_trace(&evm.context.external))
+        }
+    };
+
+    let amount_out = <U256>::abi_decode(&value, false).map_err(HPError::error)?;
+
+    Ok(SwapResult {
+        amount_out,
+        // The pool pulls the tokens itself and reverts if it receives less
+        pool_amount_in: amount_in,
+        gas_used,
+    })
+}
@@ -0,0 +1,256 @@
+use crate::{
+    cli::{CliConfig, Protocol},
+    erc20::ERC20,
+    error::{ErrorKind, HPError, Result},
+    fork::Fork,
+    report::Reserves,
+    route::{find_routes, UniswapV2Route},
+    test_swap::{SwapResult, TestSwap},
+    uniswapv2::{
+        find_pair, get_pair, get_univ2_reserves, UniV2Pair, UniswapV2, V2Fork, SUSHISWAP,
+        UNISWAP_V2,
+    },
+    uniswapv3::{get_pool, get_pools, UniswapV3},
+};
+use alloy::primitives::{Address, U256};
+
+/// A pool the token can be traded on
+pub enum Venue {
+    UniV2(UniswapV2),
+    UniV3(UniswapV3),
+    /// Token without a direct pair, reached through an intermediate token
+    UniV2Route(Box<UniswapV2Route>),
+}
+
+impl Venue {
+    pub fn print_pool(&self, config: &CliConfig) {
+        match self {
+            Venue::UniV2(v2) => config.log(format!(
+                "{} pair: {} ({} / {})",
+                v2.fork.name, v2.pair.address, v2.pair.token0, v2.pair.token1
+            )),
+            Venue::UniV3(v3) => {
+                let pool = &v3.pool;
+                config.log(format!(
+                    "Uniswap V3 pool: {} ({} / {}, fee {}, tick spacing {})",
+                    pool.address, pool.token0, pool.token1, pool.fee, pool.tick_spacing
+                ));
+                config.log(format!(
+                    "Pool liquidity: {}, tick: {}, sqrtPriceX96: {}",
+                    pool.liquidity, pool.slot0.tick, pool.slot0.sqrt_price_x96
+                ));
+            }
+            Venue::UniV2Route(route) => config.log(format!(
+                "{} route via {}: pairs {} and {}",
+                route.fork.name,
+                route.via.symbol,
+                route.quote_pair.address,
+                route.token_pair.address
+            )),
+        }
+    }
+
+    /// Whether the pools of the venue can be swapped on. Pools that were created
+    /// but never funded, or whose liquidity was removed, revert on any swap.
+    pub fn has_liquidity(&self, config: &CliConfig, fork: &mut Fork) -> Result<bool> {
+        match self {
+            Venue::UniV2(v2) => has_reserves(&v2.pair, config, fork),
+            Venue::UniV3(v3) => {
+                Ok(v3.pool.liquidity != 0 && !v3.pool.slot0.sqrt_price_x96.is_zero())
+            }
+            Venue::UniV2Route(route) => Ok(has_reserves(&route.quote_pair, config, fork)?
+                && has_reserves(&route.token_pair, config, fork)?),
+        }
+    }
+}
+
+fn has_reserves(pair: &UniV2Pair, config: &CliConfig, fork: &mut Fork) -> Result<bool> {
+    let (reserve0, reserve1) = get_univ2_reserves(pair.address, config.sender, fork)?;
+
+    Ok(!reserve0.is_zero() && !reserve1.is_zero())
+}
+
+impl TestSwap for Venue {
+    fn name(&self) -> String {
+        match self {
+            Venue::UniV2(v2) => v2.name(),
+            Venue::UniV3(v3) => v3.name(),
+            Venue::UniV2Route(route) => route.name(),
+        }
+    }
+
+    fn pool(&self) -> Address {
+        match self {
+            Venue::UniV2(v2) => v2.pool(),
+            Venue::UniV3(v3) => v3.pool(),
+            Venue::UniV2Route(route) => route.pool(),
+        }
+    }
+
+    fn router_path(&self, token_in: &ERC20, token_out: &ERC20) -> Option<(Address, Vec<Address>)> {
+        match self {
+            Venue::UniV2(v2) => v2.router_path(token_in, token_out),
+            Venue::UniV3(v3) => v3.router_path(token_in, token_out),
+            Venue::UniV2Route(route) => route.router_path(token_in, token_out),
+        }
+    }
+
+    fn balance_of(&self, token: &ERC20, owner: Address, fork: &mut Fork) -> Result<U256> {
+        match self {
+            Venue::UniV2(v2) => v2.balance_of(token, owner, fork),
+            Venue::UniV3(v3) => v3.balance_of(token, owner, fork),
+            Venue::UniV2Route(route) => route.balance_of(token, owner, fork),
+        }
+    }
+
+    fn reserves(&self, config: &CliConfig, fork: &mut Fork) -> Result<Option<Reserves>> {
+        match self {
+            Venue::UniV2(v2) => v2.reserves(config, fork),
+            Venue::UniV3(v3) => v3.reserves(config, fork),
+            Venue::UniV2Route(route) => route.reserves(config, fork),
+        }
+    }
+
+    fn route(&self) -> Option<Vec<String>> {
+        match self {
+            Venue::UniV2(v2) => v2.route(),
+            Venue::UniV3(v3) => v3.route(),
+            Venue::UniV2Route(route) => route.route(),
+        }
+    }
+
+    fn swap(
+        &self,
+        sender: Address,
+        token_in: &ERC20,
+        token_out: &ERC20,
+        amount_in: U256,
+        fork: &mut Fork,
+    ) -> Result<SwapResult> {
+        match self {
+            Venue::UniV2(v2) => v2.swap(sender, token_in, token_out, amount_in, fork),
+            Venue::UniV3(v3) => v3.swap(sender, token_in, token_out, amount_in, fork),
+            Venue::UniV2Route(route) => route.swap(sender, token_in, token_out, amount_in, fork),
+        }
+    }
+}
+
+/// Returns the venues to test based on the selected protocol.
+/// In `Auto` mode every V2 pair and every V3 fee tier pool of the token with liquidity is returned.
+/// Tokens without such a pool are tested through routes via USDC, USDT or DAI.
+pub async fn get_venues(config: &CliConfig) -> Result<Vec<Venue>> {
+    let token = &config.token.address;
+    let from_token = &config.from_token.address;
+    let client = &config.client;
+
+    let venues = match config.protocol {
+        Protocol::UniV2 => get_v2_venues(&UNISWAP_V2, config).await?,
+        Protocol::SushiV2 => get_v2_venues(&SUSHISWAP, config).await?,
+        Protocol::UniV3 => vec![Venue::UniV3(UniswapV3::new(
+            get_pool(token, from_token, config.block_number, client).await?,
+        ))],
+        Protocol::Auto => {
+            let mut venues = Vec::new();
+
+            for fork in [UNISWAP_V2, SUSHISWAP] {
+                if let Some(pair) =
+                    find_pair(&fork, token, from_token, config.block_number, client).await?
+                {
+                    venues.push(Venue::UniV2(UniswapV2::new(fork, pair, config.via_router)));
+                }
+            }
+
+            for pool in get_pools(token, from_token, config.block_number, client).await? {
+                venues.push(Venue::UniV3(UniswapV3::new(pool)));
+            }
+
+            // Empty pools revert on the buy, their inconclusive verdict would hide the real pool's
+            let mut fork = Fork::new(config)?;
+            venues = retain_liquid(venues, config, &mut fork)?;
+
+            if venues.is_empty() {
+                let mut routes = Vec::new();
+                for fork in [UNISWAP_V2, SUSHISWAP] {
+                    for route in find_routes(
+                        &fork,
+                        &config.from_token,
+                        &config.token,
+                        config.via_router,
+                        config.block_number,
+                        client,
+                    )
+                    .await?
+                    {
+                        routes.push(Venue::UniV2Route(Box::new(route)));
+                    }
+                }
+                venues = retain_liquid(routes, config, &mut fork)?;
+            }
+
+            if venues.is_empty() {
+                return Err(HPError::no_liquidity(format!(
+                    "No {} pools with liquidity found for {}",
+                    config.from_token.symbol, config.token.symbol
+                )));
+            }
+
+            venues
+        }
+    };
+
+    Ok(venues)
+}
+
+fn retain_liquid(venues: Vec<Venue>, config: &CliConfig, fork: &mut Fork) -> Result<Vec<Venue>> {
+    let mut liquid = Vec::with_capacity(venues.len());
+    for venue in venues {
+        if venue.has_liquidity(config, fork)? {
+            liquid.push(venue);
+        }
+    }
+
+    Ok(liquid)
+}
+
+/// Returns the direct pair on the V2 fork, or the routes through intermediate tokens if there is none.
+async fn get_v2_venues(fork: &V2Fork, config: &CliConfig) -> Result<Vec<Venue>> {
+    let client = &config.client;
+
+    let no_pair = match get_pair(
+        fork,
+        &config.token.address,
+        &config.from_token.address,
+        config.block_number,
+        client,
+    )
+    .await
+    {
+        Ok(pair) => {
+            return Ok(vec![Venue::UniV2(UniswapV2::new(
+                *fork,
+                pair,
+                config.via_router,
+            ))])
+        }
+        Err(e) if e.kind() == ErrorKind::NoLiquidity => e,
+        Err(e) => return Err(e),
+    };
+
+    let routes = find_routes(
+        fork,
+        &config.from_token,
+        &config.token,
+        config.via_router,
+        config.block_number,
+        client,
+    )
+    .await?;
+    if routes.is_empty() {
+        return Err(no_pair);
+    }
+
+    Ok(routes
+        .into_iter()
+        .map(|route| Venue::UniV2Route(Box::new(route)))
+        .collect())
+}